@@ -1,5 +1,5 @@
 
-use std::{collections::{HashMap, HashSet}, env, hash::{BuildHasher, Hasher}, alloc::Layout, ops::{Deref, DerefMut, Range, RangeInclusive}, ffi::{CStr, CString}, fmt, io::Write, rc::Rc};
+use std::{cell::RefCell, collections::{HashMap, HashSet}, env, marker::PhantomData, hash::{BuildHasher, Hasher}, alloc::Layout, ops::{Deref, DerefMut, Range, RangeInclusive}, ffi::{CStr, CString}, fmt, io::Write, rc::Rc};
 
 use nix::{fcntl::{self, OFlag}, libc::{self, c_char}, sys::stat::Mode};
 use nix::NixPath;
@@ -16,8 +16,55 @@ mod ioctl {
     nix::ioctl_readwrite!(ino_paths, BTRFS_IOCTL_MAGIC, 35, btrfs_ioctl_ino_path_args);
     nix::ioctl_readwrite!(logical_ino, BTRFS_IOCTL_MAGIC, 36, btrfs_ioctl_logical_ino_args);
     nix::ioctl_readwrite!(logical_ino_v2, BTRFS_IOCTL_MAGIC, 59, btrfs_ioctl_logical_ino_args);
+    nix::ioctl_read!(fs_info, BTRFS_IOCTL_MAGIC, 31, btrfs_ioctl_fs_info_args);
+    nix::ioctl_readwrite!(space_info, BTRFS_IOCTL_MAGIC, 20, btrfs_ioctl_space_args);
 }
 
+// Inode flags stored in btrfs_inode_item.flags
+pub const BTRFS_INODE_NODATACOW: u64 = 1 << 1;
+
+// Values of btrfs_file_extent_item.compression
+pub const BTRFS_COMPRESS_NONE: u8 = 0;
+pub const BTRFS_COMPRESS_ZLIB: u8 = 1;
+pub const BTRFS_COMPRESS_LZO: u8 = 2;
+pub const BTRFS_COMPRESS_ZSTD: u8 = 3;
+
+// Profiles added in 5.5, after our headers were generated. The mask replaces the one from the
+// headers, which doesn't cover them.
+pub const BTRFS_BLOCK_GROUP_RAID1C3: u32 = 1 << 9;
+pub const BTRFS_BLOCK_GROUP_RAID1C4: u32 = 1 << 10;
+pub const BTRFS_BLOCK_GROUP_PROFILE_MASK: u32 = btrfs_sys::BTRFS_BLOCK_GROUP_PROFILE_MASK | BTRFS_BLOCK_GROUP_RAID1C3 | BTRFS_BLOCK_GROUP_RAID1C4;
+
+// Search and lookup ioctls only read the trees, so read-only and frozen mounts are fine
+// for sampling. Kernels that still refuse get a message naming the mount state instead of a bare errno.
+fn ioctl_error(op: &str, err: nix::Error) -> anyhow::Error {
+    match err {
+        nix::Error::EROFS => anyhow::anyhow!("{}: filesystem is read-only or frozen ({})", op, err),
+        nix::Error::ENOTTY => anyhow::anyhow!("{}: path is not on a btrfs filesystem ({})", op, err),
+        nix::Error::EPERM | nix::Error::EACCES => anyhow::anyhow!("{}: run as root, CAP_SYS_ADMIN is needed ({})", op, err),
+        _ => anyhow::anyhow!("{}: {}", op, err),
+    }
+}
+
+// Newer kernels report extra fields in what our headers still call `reserved`,
+// but only when asked for them via `flags` (reserved[0]).
+const BTRFS_FS_INFO_FLAG_CSUM_INFO: u64 = 1 << 0;
+
+
+/// Ioctl argument structs that are followed by a variable-sized buffer.
+trait FlexibleArgs {
+    /// Whether the buffer size passed to the kernel counts the struct itself
+    /// (`btrfs_data_container`) or only the memory after it (`search_args_v2.buf`).
+    const SIZE_INCLUDES_HEADER: bool;
+}
+
+impl FlexibleArgs for btrfs_data_container {
+    const SIZE_INCLUDES_HEADER: bool = true;
+}
+
+impl FlexibleArgs for btrfs_ioctl_search_args_v2 {
+    const SIZE_INCLUDES_HEADER: bool = false;
+}
 
 #[repr(C)]
 struct WithMemAfter<T, const N: usize> {
@@ -25,6 +72,22 @@ struct WithMemAfter<T, const N: usize> {
     extra: [u8; N],
 }
 
+impl <T: FlexibleArgs, const N: usize> WithMemAfter<T, N> {
+    /// Size to hand to the kernel for this buffer.
+    fn ioctl_size(&self) -> usize {
+        if T::SIZE_INCLUDES_HEADER {
+            self.total_size()
+        } else {
+            self.extra_size()
+        }
+    }
+
+    /// Checks that the kernel didn't report writing past the extra memory.
+    fn debug_assert_written(&self, written: usize) {
+        debug_assert!(written <= self.extra_size(), "kernel wrote {} bytes into a {} byte buffer", written, self.extra_size());
+    }
+}
+
 impl <T: Sized, const N: usize> WithMemAfter<T, N> {
     fn new() -> Self {
         unsafe {
@@ -68,6 +131,110 @@ impl <T: Sized, const N: usize> DerefMut for WithMemAfter<T, N> {
     }
 }
 
+/// Like WithMemAfter, with the size of the memory after T chosen at runtime and kept on the heap.
+struct WithMemAfterOnHeap<T> {
+    // u64 elements keep T aligned
+    buf: Vec<u64>,
+    extra_size: usize,
+    value: PhantomData<T>,
+}
+
+impl <T: FlexibleArgs> WithMemAfterOnHeap<T> {
+    /// Size to hand to the kernel for this buffer.
+    fn ioctl_size(&self) -> usize {
+        if T::SIZE_INCLUDES_HEADER {
+            self.total_size()
+        } else {
+            self.extra_size()
+        }
+    }
+}
+
+impl <T: Sized> WithMemAfterOnHeap<T> {
+    /// Reuses `buf`, only T is zeroed. What a previous user left in the memory after it is
+    /// overwritten by the kernel before it is read.
+    fn from_buf(mut buf: Vec<u64>, extra_size: usize) -> Self {
+        let value_words = std::mem::size_of::<T>().div_ceil(8);
+        buf.resize(value_words + extra_size.div_ceil(8), 0);
+        buf[..value_words].fill(0);
+        WithMemAfterOnHeap {
+            buf,
+            extra_size,
+            value: PhantomData,
+        }
+    }
+
+    fn take_buf(&mut self) -> Vec<u64> {
+        self.extra_size = 0;
+        std::mem::take(&mut self.buf)
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut T {
+        self.buf.as_mut_ptr() as *mut T
+    }
+
+    fn total_size(&self) -> usize {
+        std::mem::size_of::<T>() + self.extra_size
+    }
+
+    fn extra_size(&self) -> usize {
+        self.extra_size
+    }
+
+    fn extra(&self) -> &[u8] {
+        let bytes = unsafe { std::slice::from_raw_parts(self.buf.as_ptr() as *const u8, self.buf.len() * 8) };
+        &bytes[std::mem::size_of::<T>()..][..self.extra_size]
+    }
+
+    // The kernel fills the extra region, only tests write it themselves
+    #[cfg(test)]
+    fn extra_mut(&mut self) -> &mut [u8] {
+        let bytes = unsafe { std::slice::from_raw_parts_mut(self.buf.as_mut_ptr() as *mut u8, self.buf.len() * 8) };
+        &mut bytes[std::mem::size_of::<T>()..][..self.extra_size]
+    }
+}
+
+impl <T: Sized> Deref for WithMemAfterOnHeap<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.buf.as_ptr() as *const T) }
+    }
+}
+
+impl <T: Sized> DerefMut for WithMemAfterOnHeap<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.as_mut_ptr() }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FsInfo {
+    pub nodesize: u32,
+    pub sectorsize: u32,
+    pub csum_size: u16,
+}
+
+pub fn fs_info(fd: i32) -> Result<FsInfo> {
+    let mut args: btrfs_ioctl_fs_info_args = unsafe { std::mem::zeroed() };
+    args.reserved[0] = BTRFS_FS_INFO_FLAG_CSUM_INFO;
+    unsafe {
+        ioctl::fs_info(fd, &mut args).map_err(|err| ioctl_error("fs_info", err))?;
+    }
+    // csum_type and csum_size share the old reserved32 slot,
+    // kernels without csum info only support 4-byte crc32c
+    let csum_size = if args.reserved[0] & BTRFS_FS_INFO_FLAG_CSUM_INFO != 0 {
+        (args.reserved32 >> 16) as u16
+    } else {
+        4
+    };
+    Ok(FsInfo {
+        nodesize: args.nodesize,
+        sectorsize: args.sectorsize,
+        csum_size,
+    })
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct LogicalInoItem {
@@ -76,19 +243,62 @@ pub struct LogicalInoItem {
     pub root: u64,
 }
 
+// Allocated and used bytes of every block group type and profile (logical sizes, like btrfs filesystem df)
+pub fn space_info(fd: i32) -> Result<Vec<btrfs_ioctl_space_info>> {
+    // the first call only reports how many there are
+    let mut args: btrfs_ioctl_space_args = unsafe { std::mem::zeroed() };
+    unsafe {
+        ioctl::space_info(fd, &mut args).map_err(|err| ioctl_error("space_info", err))?;
+    }
+    let count = args.total_spaces as usize;
+    // u64 elements keep the args aligned
+    let mut buf = vec![0u64; (std::mem::size_of::<btrfs_ioctl_space_args>() + count * std::mem::size_of::<btrfs_ioctl_space_info>()) / 8];
+    let args = buf.as_mut_ptr() as *mut btrfs_ioctl_space_args;
+    unsafe {
+        (*args).space_slots = count as u64;
+        ioctl::space_info(fd, &mut *args).map_err(|err| ioctl_error("space_info", err))?;
+        // block groups of a new type may have appeared in between, they are left out
+        let count = ((*args).total_spaces as usize).min(count);
+        Ok((*args).spaces.as_slice(count).to_vec())
+    }
+}
+
+// LOGICAL_INO_V2 accepts result buffers up to this size
+const MAX_LOGICAL_INO_SIZE: usize = 16 * 1024 * 1024;
+
+// An extent is referenced by more inodes than fit into the largest logical_ino buffer
+#[derive(Debug)]
+pub struct TooManyRefs;
+
+impl fmt::Display for TooManyRefs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "logical_ino: references don't fit into {} bytes", MAX_LOGICAL_INO_SIZE)
+    }
+}
+
+impl std::error::Error for TooManyRefs {}
+
 pub fn logical_ino(fd: i32, logical: u64, ignoring_offset: bool, mut cb: impl FnMut(Result<&[LogicalInoItem]>)) {
     let mut data = WithMemAfter::<btrfs_data_container, 4096>::new();
+    let flags = if ignoring_offset {BTRFS_LOGICAL_INO_ARGS_IGNORE_OFFSET as u64} else {0};
 
     let mut args = btrfs_ioctl_logical_ino_args{
         logical: logical,
-        size: data.total_size() as u64,
+        size: data.ioctl_size() as u64,
         reserved: Default::default(),
-        flags: if ignoring_offset {BTRFS_LOGICAL_INO_ARGS_IGNORE_OFFSET as u64} else {0},
+        flags,
         inodes: data.as_mut_ptr() as u64,
     };
     unsafe {
         match ioctl::logical_ino_v2(fd, &mut args) {
+            Ok(_) if data.elem_missed > 0 => {
+                // heavily shared extent, retry once with room for all of its references
+                let needed = std::mem::size_of::<btrfs_data_container>() + (data.elem_cnt + data.elem_missed) as usize * std::mem::size_of::<u64>();
+                tracing::debug!(logical, needed, "logical_ino: references don't fit, retrying on the heap");
+                logical_ino_on_heap(fd, logical, flags, needed, cb);
+            },
             Ok(_) => {
+                data.debug_assert_written(data.elem_cnt as usize * std::mem::size_of::<u64>());
                 let inodes = std::slice::from_raw_parts(
                     data.extra_ptr() as *const LogicalInoItem, 
                     (data.elem_cnt / 3) as usize,
@@ -96,13 +306,51 @@ pub fn logical_ino(fd: i32, logical: u64, ignoring_offset: bool, mut cb: impl Fn
                 cb(Ok(inodes));
             },
             Err(err) => {
-                cb(Err(anyhow::anyhow!(err.to_string())));
+                cb(Err(ioctl_error("logical_ino", err)));
             },
         }
     }  
 }
 
-pub fn ino_lookup(fd: i32, root: u64, inum: u64, mut cb: impl FnMut(Result<&CStr>)){
+fn logical_ino_on_heap(fd: i32, logical: u64, flags: u64, size: usize, mut cb: impl FnMut(Result<&[LogicalInoItem]>)) {
+    if size > MAX_LOGICAL_INO_SIZE {
+        cb(Err(anyhow::Error::new(TooManyRefs)));
+        return;
+    }
+    // u64 elements keep the container aligned
+    let mut buf = vec![0u64; size.div_ceil(8)];
+    let container = buf.as_mut_ptr() as *mut btrfs_data_container;
+    let mut args = btrfs_ioctl_logical_ino_args{
+        logical,
+        size: (buf.len() * std::mem::size_of::<u64>()) as u64,
+        reserved: Default::default(),
+        flags,
+        inodes: container as u64,
+    };
+    unsafe {
+        match ioctl::logical_ino_v2(fd, &mut args) {
+            // references may have been added since the first call
+            Ok(_) if (*container).elem_missed > 0 => {
+                cb(Err(anyhow::Error::new(TooManyRefs)));
+            },
+            Ok(_) => {
+                let inodes = std::slice::from_raw_parts(
+                    (container as *const u8).add(std::mem::size_of::<btrfs_data_container>()) as *const LogicalInoItem,
+                    ((*container).elem_cnt / 3) as usize,
+                );
+                cb(Ok(inodes));
+            },
+            Err(err) => {
+                cb(Err(ioctl_error("logical_ino", err)));
+            },
+        }
+    }
+}
+
+// The path is assembled from the inode refs of `root` itself, relative to that subvolume's
+// root directory. It never crosses into other filesystems mounted below the btrfs mount point,
+// so sampled paths always describe data owned by this filesystem. It is converted lossily to UTF-8.
+pub fn ino_lookup_sync(fd: i32, root: u64, inum: u64) -> Result<String> {
     let mut args = btrfs_ioctl_ino_lookup_args{
         treeid: root,
         objectid: inum,
@@ -111,16 +359,16 @@ pub fn ino_lookup(fd: i32, root: u64, inum: u64, mut cb: impl FnMut(Result<&CStr
 
     unsafe {
         match ioctl::ino_lookup(fd, &mut args) {
-            Ok(_) => {
-                cb(Ok(CStr::from_ptr(args.name.as_ptr())));
-            },
-            Err(err) => {
-                cb(Err(anyhow::anyhow!(err.to_string())));
-            },
+            Ok(_) => Ok(CStr::from_ptr(args.name.as_ptr()).to_string_lossy().into_owned()),
+            // e.g. the inode was deleted after it was sampled
+            Err(nix::Error::ENOENT) => Err(anyhow::anyhow!("ino_lookup: inode {} does not exist in root {}", inum, root)),
+            Err(err) => Err(ioctl_error("ino_lookup", err)),
         }
     }
 }
 
+// Ordered like keys in a tree: by objectid, then type, then offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SearchKey {
     pub objectid: u64,
     pub typ: u8,
@@ -144,15 +392,19 @@ impl SearchKey {
 
     
     
-    pub fn next(&self) -> Self {
+    // The key right after this one, None for SearchKey::MAX
+    pub fn next(&self) -> Option<Self> {
         let (offset, carry1) = self.offset.carrying_add(1, false);
         let (typ, carry2) = self.typ.carrying_add(0, carry1);
-        let (objectid, _) = self.objectid.carrying_add(0, carry2);
-        SearchKey {
+        let (objectid, carry3) = self.objectid.carrying_add(0, carry2);
+        if carry3 {
+            return None;
+        }
+        Some(SearchKey {
             objectid,
             typ,
             offset,
-        }
+        })
     }
 
     fn from(h: &btrfs_ioctl_search_header) -> Self {
@@ -164,20 +416,38 @@ impl SearchKey {
     }
 }
 
-unsafe fn get_and_move(ptr: &mut *const u8, n: usize) -> *const u8 {
-    let res = *ptr;
-    *ptr = (*ptr).add(n);
-    res
+
+// Items returned by one search ioctl have to fit into this. Larger buffers mean fewer round
+// trips on big trees like the extent tree, the kernel accepts up to 16 MiB. This is the size
+// searches always used, bench_search_buf_size compares it to larger ones.
+const SEARCH_BUF_SIZE: usize = 16 * 1024;
+
+// Most searches are lookups of a few items done for every sample. Their buffers are kept for
+// the next search instead of allocating and zeroing SEARCH_BUF_SIZE bytes each time.
+const MAX_SPARE_SEARCH_BUFS: usize = 4;
+
+thread_local! {
+    static SPARE_SEARCH_BUFS: RefCell<Vec<Vec<u64>>> = const { RefCell::new(Vec::new()) };
 }
 
-unsafe fn get_and_move_typed<T: Sized>(ptr: &mut *const u8) -> *const T {
-    let res = *ptr as *const T;
-    *ptr = (*ptr).add(std::mem::size_of::<T>());
-    res
+// Iterator over the items of `range` in key order. A new search ioctl is issued, starting
+// right after the last returned key, whenever the items of the previous one are used up.
+pub struct TreeSearch {
+    fd: i32,
+    args: WithMemAfterOnHeap<btrfs_ioctl_search_args_v2>,
+    // offset of the next item in the buffer and how many of the last ioctl's items are left
+    pos: usize,
+    remaining: u32,
+    done: bool,
 }
 
-pub fn tree_search_cb(fd: i32, tree_id: u64, range: RangeInclusive<SearchKey>, mut cb: impl FnMut(&btrfs_ioctl_search_header, &[u8])) -> Result<()> {
-    let mut args = WithMemAfter::<btrfs_ioctl_search_args_v2, {16*1024}>::new();
+pub fn tree_search(fd: i32, tree_id: u64, range: RangeInclusive<SearchKey>) -> TreeSearch {
+    tree_search_with_buf_size(fd, tree_id, range, SEARCH_BUF_SIZE)
+}
+
+fn tree_search_with_buf_size(fd: i32, tree_id: u64, range: RangeInclusive<SearchKey>, buf_size: usize) -> TreeSearch {
+    let buf = SPARE_SEARCH_BUFS.with(|bufs| bufs.borrow_mut().pop()).unwrap_or_default();
+    let mut args = WithMemAfterOnHeap::<btrfs_ioctl_search_args_v2>::from_buf(buf, buf_size);
     args.key = btrfs_ioctl_search_key{
         tree_id: tree_id,
         min_objectid: range.start().objectid,
@@ -197,67 +467,841 @@ pub fn tree_search_cb(fd: i32, tree_id: u64, range: RangeInclusive<SearchKey>, m
         unused4: 0,
         
     };
-    args.buf_size = args.extra_size() as u64;
+    args.buf_size = args.ioctl_size() as u64;
+    TreeSearch {
+        fd,
+        args,
+        pos: 0,
+        remaining: 0,
+        done: false,
+    }
+}
 
-    loop {
-        args.key.nr_items = u32::MAX;
-        unsafe {
-            ioctl::search_v2(fd, args.as_mut_ptr())?;
+impl Iterator for TreeSearch {
+    type Item = Result<(btrfs_ioctl_search_header, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item().map(|item| item.map(|(search_header, data)| (search_header, self.args.extra()[data].to_vec())))
+    }
+}
+
+impl TreeSearch {
+    // Header of the next item and where its data is in the buffer, valid until the next call
+    fn next_item(&mut self) -> Option<Result<(btrfs_ioctl_search_header, Range<usize>)>> {
+        if self.remaining == 0 {
+            if self.done {
+                return None;
+            }
+            self.args.key.nr_items = u32::MAX;
+            if let Err(err) = unsafe { ioctl::search_v2(self.fd, self.args.as_mut_ptr()) } {
+                self.done = true;
+                return Some(Err(ioctl_error("tree_search", err)));
+            }
+            if self.args.key.nr_items == 0 {
+                self.done = true;
+                return None;
+            }
+            self.remaining = self.args.key.nr_items;
+            self.pos = 0;
         }
-        if args.key.nr_items == 0 {
-            break
+
+        // the item count and lengths come from the kernel, every item is checked to lie within
+        // the buffer before it is read
+        let extra = self.args.extra();
+        let header_size = std::mem::size_of::<btrfs_ioctl_search_header>();
+        // items are packed back to back, so headers are not necessarily aligned
+        let search_header = match extra.get(self.pos..self.pos + header_size) {
+            Some(header) => unsafe { std::ptr::read_unaligned(header.as_ptr() as *const btrfs_ioctl_search_header) },
+            None => return Some(Err(self.overrun())),
+        };
+        let data_start = self.pos + header_size;
+        let data = data_start..data_start + search_header.len as usize;
+        if data.end > extra.len() {
+            return Some(Err(self.overrun()));
         }
+        self.pos = data.end;
+        self.remaining -= 1;
 
-        let mut ptr = args.buf.as_ptr() as *const u8;
-        let mut last_search_header: *const btrfs_ioctl_search_header = std::ptr::null();
-        for _ in 0..args.key.nr_items {
-            let search_header = unsafe {
-                get_and_move_typed::<btrfs_ioctl_search_header>(&mut ptr)
-            };
+        if self.remaining == 0 {
+            match SearchKey::from(&search_header).next() {
+                Some(min_key) => {
+                    self.args.key.min_objectid = min_key.objectid;
+                    self.args.key.min_type = min_key.typ as u32;
+                    self.args.key.min_offset = min_key.offset;
+                },
+                // nothing can come after the largest key, searching again would start over
+                None => self.done = true,
+            }
+        }
+        Some(Ok((search_header, data)))
+    }
 
-            let data = unsafe {
-                std::slice::from_raw_parts(
-                    get_and_move(&mut ptr, (*search_header).len as usize),
-                    (*search_header).len as usize
-                )
-            };
-            last_search_header = search_header;
-            unsafe {
-                cb(&*search_header, data);
+    // Ends the search on an item reported past the end of the buffer
+    fn overrun(&mut self) -> anyhow::Error {
+        self.remaining = 0;
+        self.done = true;
+        anyhow::anyhow!("tree_search: item at {} runs past the {} byte buffer", self.pos, self.args.extra_size())
+    }
+}
+
+impl Drop for TreeSearch {
+    fn drop(&mut self) {
+        let buf = self.args.take_buf();
+        SPARE_SEARCH_BUFS.with(|bufs| {
+            let mut bufs = bufs.borrow_mut();
+            if bufs.len() < MAX_SPARE_SEARCH_BUFS {
+                bufs.push(buf);
             }
+        });
+    }
+}
+
+// Bytes a chunk takes up on its devices including mirrors and parity, e.g. twice its length for DUP or RAID1
+pub fn chunk_physical_length(chunk: &btrfs_chunk) -> u64 {
+    let num_stripes = chunk.num_stripes as u64;
+    let data_stripes = match (chunk.type_ as u32) & BTRFS_BLOCK_GROUP_PROFILE_MASK {
+        BTRFS_BLOCK_GROUP_RAID0 => num_stripes,
+        BTRFS_BLOCK_GROUP_RAID10 => num_stripes / (chunk.sub_stripes as u64).max(1),
+        BTRFS_BLOCK_GROUP_RAID5 => num_stripes.saturating_sub(1),
+        BTRFS_BLOCK_GROUP_RAID6 => num_stripes.saturating_sub(2),
+        // single, DUP and the RAID1 variants store one full copy per stripe
+        _ => 1,
+    };
+    chunk.length / data_stripes.max(1) * num_stripes.max(1)
+}
+
+// Kind of a block group, mixed block groups hold both data and metadata
+pub fn block_group_type_name(flags: u64) -> &'static str {
+    let flags = flags as u32;
+    let data = flags & BTRFS_BLOCK_GROUP_DATA != 0;
+    let metadata = flags & BTRFS_BLOCK_GROUP_METADATA != 0;
+    match (data, metadata) {
+        (true, true) => "DATA+METADATA",
+        (true, false) => "DATA",
+        (false, true) => "METADATA",
+        _ if flags & BTRFS_BLOCK_GROUP_SYSTEM != 0 => "SYSTEM",
+        _ => "UNKNOWN",
+    }
+}
+
+// Profile name like `btrfs filesystem df` prints it
+pub fn profile_name(flags: u64) -> &'static str {
+    match (flags as u32) & BTRFS_BLOCK_GROUP_PROFILE_MASK {
+        0 => "single",
+        BTRFS_BLOCK_GROUP_DUP => "dup",
+        BTRFS_BLOCK_GROUP_RAID0 => "raid0",
+        BTRFS_BLOCK_GROUP_RAID1 => "raid1",
+        BTRFS_BLOCK_GROUP_RAID1C3 => "raid1c3",
+        BTRFS_BLOCK_GROUP_RAID1C4 => "raid1c4",
+        BTRFS_BLOCK_GROUP_RAID10 => "raid10",
+        BTRFS_BLOCK_GROUP_RAID5 => "raid5",
+        BTRFS_BLOCK_GROUP_RAID6 => "raid6",
+        _ => "unknown",
+    }
+}
+
+// An item too short for what it should hold, e.g. a struct or the name following it
+#[derive(Debug)]
+pub struct MalformedItem {
+    pub objectid: u64,
+    pub typ: u32,
+    pub offset: u64,
+    pub len: usize,
+    pub expected: usize,
+}
+
+impl MalformedItem {
+    fn new(sh: &btrfs_ioctl_search_header, len: usize, expected: usize) -> Self {
+        Self {
+            objectid: sh.objectid,
+            typ: sh.type_,
+            offset: sh.offset,
+            len,
+            expected,
         }
+    }
+}
 
-        let min_key = unsafe {
-            SearchKey::from(&*last_search_header).next()
-        };
+impl fmt::Display for MalformedItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed item ({},{},{}): {} bytes, expected at least {}", self.objectid, self.typ, self.offset, self.len, self.expected)
+    }
+}
+
+impl std::error::Error for MalformedItem {}
+
+// Item data as a T, so that a truncated or unexpected item is an error instead of read past its end
+pub fn read_item<T>(sh: &btrfs_ioctl_search_header, data: &[u8]) -> Result<T, MalformedItem> {
+    if data.len() < std::mem::size_of::<T>() {
+        return Err(MalformedItem::new(sh, data.len(), std::mem::size_of::<T>()));
+    }
+    Ok(unsafe {
+        std::ptr::read_unaligned(data.as_ptr() as *const T)
+    })
+}
+
+// The name stored after a struct of `name_start` bytes
+fn item_name<'a>(sh: &btrfs_ioctl_search_header, data: &'a [u8], name_start: usize, name_len: usize) -> Result<&'a [u8], MalformedItem> {
+    data.get(name_start..name_start + name_len).ok_or_else(|| MalformedItem::new(sh, data.len(), name_start + name_len))
+}
 
-        args.key.min_objectid = min_key.objectid;
-        args.key.min_type = min_key.typ as u32;
-        args.key.min_offset = min_key.offset;
+// Like tree_search, but the data is passed straight from the search buffer instead of copied
+pub fn tree_search_cb(fd: i32, tree_id: u64, range: RangeInclusive<SearchKey>, mut cb: impl FnMut(&btrfs_ioctl_search_header, &[u8])) -> Result<()> {
+    let mut search = tree_search(fd, tree_id, range);
+    while let Some(item) = search.next_item() {
+        let (search_header, data) = item?;
+        cb(&search_header, &search.args.extra()[data]);
     }
+    Ok(())
+}
 
+// Calls back with every item of a key range of a tree in key order, tree_search_cb on a
+// filesystem except in tests
+pub type SearchItems<'a> = &'a dyn Fn(u64, RangeInclusive<SearchKey>, &mut dyn FnMut(&btrfs_ioctl_search_header, &[u8])) -> Result<()>;
+
+// SearchItems over canned (tree id, header, data) items, which have to be in key order
+#[cfg(test)]
+pub fn search_canned(items: &[(u64, btrfs_ioctl_search_header, Vec<u8>)], tree_id: u64, range: RangeInclusive<SearchKey>, cb: &mut dyn FnMut(&btrfs_ioctl_search_header, &[u8])) -> Result<()> {
+    for (id, sh, data) in items {
+        if *id == tree_id && range.contains(&SearchKey::from(sh)) {
+            cb(sh, data);
+        }
+    }
     Ok(())
 }
 
+// A canned item of `tree_id` for search_canned
+#[cfg(test)]
+pub fn canned_item(tree_id: u64, objectid: u64, typ: u32, offset: u64, data: Vec<u8>) -> (u64, btrfs_ioctl_search_header, Vec<u8>) {
+    let sh = btrfs_ioctl_search_header { transid: 0, objectid, offset, type_: typ, len: data.len() as u32 };
+    (tree_id, sh, data)
+}
+
 
-pub fn find_root_backref(fd:i32, root_id: u64) -> Option<(String, u64)> {
+// Errors with MalformedItem when the backref is too short for its name
+pub fn find_root_backref(fd:i32, root_id: u64) -> Result<Option<(String, u64)>> {
     let mut res: Option<(String, u64)> = None;
-    tree_search_cb(fd, BTRFS_ROOT_TREE_OBJECTID as u64, SearchKey::range_fixed_id_type(root_id, BTRFS_ROOT_BACKREF_KEY as u8), |sh, data| {
+    for item in tree_search(fd, BTRFS_ROOT_TREE_OBJECTID as u64, SearchKey::range_fixed_id_type(root_id, BTRFS_ROOT_BACKREF_KEY as u8)) {
+        let (sh, data) = item?;
+        if sh.type_ == BTRFS_ROOT_BACKREF_KEY {
+            let root_ref: btrfs_root_ref = read_item(&sh, &data)?;
+            let name = item_name(&sh, &data, std::mem::size_of::<btrfs_root_ref>(), root_ref.name_len as usize)?;
+            res = Some((String::from_utf8_lossy(name).into_owned(), sh.offset));
+        }
+    }
+    Ok(res)
+}
+
+pub fn find_inode_item(fd: i32, root: u64, inum: u64) -> Result<Option<btrfs_inode_item>> {
+    let mut res: Option<btrfs_inode_item> = None;
+    for item in tree_search(fd, root, SearchKey::range_fixed_id_type(inum, BTRFS_INODE_ITEM_KEY as u8)) {
+        let (sh, data) = item?;
+        if sh.type_ == BTRFS_INODE_ITEM_KEY {
+            res = Some(read_item(&sh, &data)?);
+        }
+    }
+    Ok(res)
+}
+
+pub fn parse_tree_id(s: &str) -> std::result::Result<u64, String> {
+    let tree_id = match s.to_ascii_uppercase().as_str() {
+        "ROOT" => BTRFS_ROOT_TREE_OBJECTID,
+        "EXTENT" => BTRFS_EXTENT_TREE_OBJECTID,
+        "CHUNK" => BTRFS_CHUNK_TREE_OBJECTID,
+        "DEV" => BTRFS_DEV_TREE_OBJECTID,
+        "FS" => BTRFS_FS_TREE_OBJECTID,
+        "CSUM" => BTRFS_CSUM_TREE_OBJECTID,
+        "QUOTA" => BTRFS_QUOTA_TREE_OBJECTID,
+        "UUID" => BTRFS_UUID_TREE_OBJECTID,
+        "FREE_SPACE" => BTRFS_FREE_SPACE_TREE_OBJECTID,
+        _ => return s.parse::<u64>().map_err(|_| format!("unknown tree: {}", s)),
+    };
+    Ok(tree_id as u64)
+}
+
+// Name parse_tree_id accepts for a tree id
+pub fn tree_name(tree_id: u64) -> Option<&'static str> {
+    // the constants are u32, larger ids would match on their low bits
+    if tree_id > u32::MAX as u64 {
+        return None;
+    }
+    let name = match tree_id as u32 {
+        BTRFS_ROOT_TREE_OBJECTID => "ROOT",
+        BTRFS_EXTENT_TREE_OBJECTID => "EXTENT",
+        BTRFS_CHUNK_TREE_OBJECTID => "CHUNK",
+        BTRFS_DEV_TREE_OBJECTID => "DEV",
+        BTRFS_FS_TREE_OBJECTID => "FS",
+        BTRFS_CSUM_TREE_OBJECTID => "CSUM",
+        BTRFS_QUOTA_TREE_OBJECTID => "QUOTA",
+        BTRFS_UUID_TREE_OBJECTID => "UUID",
+        BTRFS_FREE_SPACE_TREE_OBJECTID => "FREE_SPACE",
+        _ => return None,
+    };
+    Some(name)
+}
+
+pub fn find_inode_parent(fd: i32, root: u64, inum: u64) -> Result<Option<u64>> {
+    let mut res: Option<u64> = None;
+    tree_search_cb(fd, root, SearchKey::range_fixed_id_type(inum, BTRFS_INODE_REF_KEY as u8), |sh, _data| {
         match sh.type_ {
-            BTRFS_ROOT_BACKREF_KEY => {
-                let root_ref = unsafe {
-                    &*(data.as_ptr() as *const btrfs_root_ref)
-                };
-                let name = unsafe {
-                    std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-                        data.as_ptr().add(std::mem::size_of::<btrfs_root_ref>()),
-                        root_ref.name_len as usize
-                    ))
+            // with hardlinks there's one ref per parent, ino_lookup follows the first one as well
+            BTRFS_INODE_REF_KEY if res.is_none() => {
+                res = Some(sh.offset);
+            },
+            _ => {}
+        };
+    })?;
+    Ok(res)
+}
+
+// Every (parent directory, name) an inode is linked as. An INODE_REF item holds the names
+// within one directory, INODE_EXTREF items are used once those don't fit.
+pub fn inode_links(fd: i32, root: u64, inum: u64) -> Result<Vec<(u64, String)>> {
+    let mut res = Vec::new();
+    let range = SearchKey::new(inum, BTRFS_INODE_REF_KEY as u8, u64::MIN)..=SearchKey::new(inum, BTRFS_INODE_EXTREF_KEY as u8, u64::MAX);
+    tree_search_cb(fd, root, range, |sh, data| {
+        let (header_size, name_len_offset) = match sh.type_ {
+            BTRFS_INODE_REF_KEY => (std::mem::size_of::<btrfs_inode_ref>(), 8),
+            BTRFS_INODE_EXTREF_KEY => (std::mem::size_of::<btrfs_inode_extref>(), 16),
+            _ => return,
+        };
+        // entries are packed back to back, each a header followed by the name
+        let mut pos = 0;
+        while let Some(header) = data.get(pos..pos + header_size) {
+            let name_len = u16::from_le_bytes(header[name_len_offset..name_len_offset + 2].try_into().unwrap()) as usize;
+            let name = match data.get(pos + header_size..pos + header_size + name_len) {
+                Some(name) => String::from_utf8_lossy(name).into_owned(),
+                None => break,
+            };
+            let parent = if sh.type_ == BTRFS_INODE_REF_KEY {
+                sh.offset
+            } else {
+                u64::from_le_bytes(header[0..8].try_into().unwrap())
+            };
+            res.push((parent, name));
+            pos += header_size + name_len;
+        }
+    })?;
+    Ok(res)
+}
+
+// Free ranges of the block group at [start, start + length) in increasing order, according to the
+// free space tree (space_cache=v2). A block group keeps its free space either as extent items
+// keyed (start, FREE_SPACE_EXTENT, length) or, once fragmented, as bitmaps keyed the same way
+// whose bits stand for one sector each.
+pub fn free_space(fd: i32, start: u64, length: u64, sectorsize: u32) -> Result<Vec<Range<u64>>> {
+    let mut res: Vec<Range<u64>> = Vec::new();
+    let mut push = |range: Range<u64>| match res.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => res.push(range),
+    };
+    let range = SearchKey::new(start, BTRFS_FREE_SPACE_EXTENT_KEY as u8, u64::MIN)..=SearchKey::new(start + length - 1, BTRFS_FREE_SPACE_BITMAP_KEY as u8, u64::MAX);
+    tree_search_cb(fd, BTRFS_FREE_SPACE_TREE_OBJECTID as u64, range, |sh, data| {
+        match sh.type_ {
+            BTRFS_FREE_SPACE_EXTENT_KEY => push(sh.objectid..sh.objectid + sh.offset),
+            BTRFS_FREE_SPACE_BITMAP_KEY => {
+                let sectors = (sh.offset / sectorsize as u64) as usize;
+                for i in (0..sectors).filter(|i| data.get(i / 8).is_some_and(|b| b >> (i % 8) & 1 != 0)) {
+                    let sector = sh.objectid + i as u64 * sectorsize as u64;
+                    push(sector..sector + sectorsize as u64);
+                }
+            },
+            _ => {}
+        };
+    })?;
+    Ok(res)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CsumUsage {
+    /// Size of all checksums, the items of the csum tree without their headers
+    pub csum_bytes: u64,
+    pub checksummed_bytes: u64,
+}
+
+// Walks the whole csum tree. The checksum size depends on the algorithm (4 bytes for crc32c,
+// up to 32 for sha256 and blake2b) and is taken from fs_info.
+pub fn csum_usage(fd: i32, fs_info: &FsInfo) -> Result<CsumUsage> {
+    let mut csum_bytes = 0;
+    let objectid = BTRFS_EXTENT_CSUM_OBJECTID as u64;
+    tree_search_cb(fd, BTRFS_CSUM_TREE_OBJECTID as u64, SearchKey::range_fixed_id_type(objectid, BTRFS_EXTENT_CSUM_KEY as u8), |sh, data| {
+        if sh.type_ == BTRFS_EXTENT_CSUM_KEY {
+            csum_bytes += data.len() as u64;
+        }
+    })?;
+    Ok(CsumUsage {
+
+        csum_bytes,
+        checksummed_bytes: csum_bytes / fs_info.csum_size.max(1) as u64 * fs_info.sectorsize as u64,
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SubvolSize {
+    pub referenced: u64,
+    /// None when it can't be determined without walking every other subvolume
+    pub exclusive: Option<u64>,
+}
+
+// Level 0 qgroup of a subvolume, None when quotas are disabled or the numbers are not reliable
+fn find_qgroup_info(search: SearchItems, root_id: u64) -> Option<btrfs_qgroup_info_item> {
+    let mut status_flags: Option<u64> = None;
+    search(BTRFS_QUOTA_TREE_OBJECTID as u64, SearchKey::range_fixed_id_type(0, BTRFS_QGROUP_STATUS_KEY as u8), &mut |sh, data| {
+        if sh.type_ == BTRFS_QGROUP_STATUS_KEY {
+            status_flags = read_item::<btrfs_qgroup_status_item>(sh, data).ok().map(|status| status.flags);
+        }
+    }).ok()?;
+    let bad_flags = (BTRFS_QGROUP_STATUS_FLAG_RESCAN | BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT) as u64;
+    if status_flags? & bad_flags != 0 {
+        return None;
+    }
+
+    let mut res: Option<btrfs_qgroup_info_item> = None;
+    let range = SearchKey::new(0, BTRFS_QGROUP_INFO_KEY as u8, root_id)..=SearchKey::new(0, BTRFS_QGROUP_INFO_KEY as u8, root_id);
+    search(BTRFS_QUOTA_TREE_OBJECTID as u64, range, &mut |sh, data| {
+        if sh.type_ == BTRFS_QGROUP_INFO_KEY {
+            res = read_item(sh, data).ok();
+        }
+
+    }).ok()?;
+    res
+}
+
+// Uses the qgroup numbers when quotas are enabled and consistent. Otherwise every item of the
+// subvolume's tree is read to sum up the data extents it references (each extent counted once),
+// which costs a full walk of that tree and can't tell which extents are shared with other
+// subvolumes, so `exclusive` is None.
+pub fn subvol_size(fd: i32, root_id: u64) -> Result<SubvolSize> {
+    subvol_size_from(&|tree_id, range, cb| tree_search_cb(fd, tree_id, range, cb), root_id)
+}
+
+fn subvol_size_from(search: SearchItems, root_id: u64) -> Result<SubvolSize> {
+    if let Some(info) = find_qgroup_info(search, root_id) {
+        return Ok(SubvolSize {
+            referenced: info.rfer,
+            exclusive: Some(info.excl),
+        });
+    }
+
+    let mut extents = HashSet::new();
+    let mut referenced = 0;
+    search(root_id, SearchKey::ALL, &mut |sh, data| {
+        match sh.type_ {
+            BTRFS_EXTENT_DATA_KEY if data.len() >= std::mem::size_of::<btrfs_file_extent_item>() => {
+                let extent = unsafe {
+                    std::ptr::read_unaligned(data.as_ptr() as *const btrfs_file_extent_item)
                 };
-                res = Some((name.to_owned(), sh.offset));
+                // disk_bytenr 0 is a hole
+                if extent.type_ as u32 != BTRFS_FILE_EXTENT_INLINE && extent.disk_bytenr != 0 && extents.insert(extent.disk_bytenr) {
+                    referenced += extent.disk_num_bytes;
+                }
             },
             _ => {}
         };
-    }).unwrap();
+    })?;
+    Ok(SubvolSize {
+        referenced,
+        exclusive: None,
+    })
+}
+
+// The "default" entry of the root tree directory points at the subvolume mounted
+// when no subvol/subvolid mount option is given.
+pub fn find_default_subvol(fd: i32) -> Result<Option<u64>> {
+    find_default_subvol_from(&|tree_id, range, cb| tree_search_cb(fd, tree_id, range, cb))
+}
+
+// Errors with MalformedItem when a dir item is too short for its name
+fn find_default_subvol_from(search: SearchItems) -> Result<Option<u64>> {
+    let mut res: Result<Option<u64>> = Ok(None);
+    search(BTRFS_ROOT_TREE_OBJECTID as u64, SearchKey::range_fixed_id_type(BTRFS_ROOT_TREE_DIR_OBJECTID as u64, BTRFS_DIR_ITEM_KEY as u8), &mut |sh, data| {
+        if res.is_err() || sh.type_ != BTRFS_DIR_ITEM_KEY {
+            return;
+        }
+        let location = read_item::<btrfs_dir_item>(sh, data).and_then(|dir_item| {
+            let name = item_name(sh, data, std::mem::size_of::<btrfs_dir_item>(), dir_item.name_len as usize)?;
+            Ok((name == b"default").then_some(dir_item.location.objectid))
+        });
+        match location {
+            Ok(Some(objectid)) => res = Ok(Some(objectid)),
+            Ok(None) => {},
+            Err(err) => res = Err(err.into()),
+        }
+    })?;
     res
-}
\ No newline at end of file
+}
+
+// Ids of all subvolumes and snapshots except the top level one (5)
+pub fn list_subvols(fd: i32) -> Result<Vec<u64>> {
+    let mut res: Vec<u64> = Vec::new();
+    let range = SearchKey::new(BTRFS_FIRST_FREE_OBJECTID as u64, BTRFS_ROOT_ITEM_KEY as u8, u64::MIN)..=SearchKey::new(BTRFS_LAST_FREE_OBJECTID as u64, BTRFS_ROOT_ITEM_KEY as u8, u64::MAX);
+    for item in tree_search(fd, BTRFS_ROOT_TREE_OBJECTID as u64, range) {
+        let (sh, _) = item?;
+        // the range also covers the backrefs and other items of these ids
+        if sh.type_ == BTRFS_ROOT_ITEM_KEY && res.last() != Some(&sh.objectid) {
+            res.push(sh.objectid);
+        }
+    }
+    Ok(res)
+}
+
+// Data extents (including preallocated ones) never cover more than this much of a file
+const MAX_FILE_EXTENT_SIZE: u64 = 256 * 1024 * 1024;
+
+// File extent item of `inum` covering the file offset, e.g. as returned by logical_ino
+pub fn find_file_extent(fd: i32, root: u64, inum: u64, file_offset: u64) -> Result<Option<btrfs_file_extent_item>> {
+    let mut res: Option<btrfs_file_extent_item> = None;
+    let range = SearchKey::new(inum, BTRFS_EXTENT_DATA_KEY as u8, file_offset.saturating_sub(MAX_FILE_EXTENT_SIZE))..=SearchKey::new(inum, BTRFS_EXTENT_DATA_KEY as u8, file_offset);
+    tree_search_cb(fd, root, range, |sh, data| {
+        match sh.type_ {
+            // items come in key order, the last one starts closest to file_offset
+            BTRFS_EXTENT_DATA_KEY if data.len() >= std::mem::size_of::<btrfs_file_extent_item>() => {
+                res = Some(unsafe {
+                    std::ptr::read_unaligned(data.as_ptr() as *const btrfs_file_extent_item)
+                });
+            },
+            _ => {}
+        };
+    })?;
+    Ok(res)
+}
+
+// Owner of a tree block according to its backrefs in the extent tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeBlockOwner {
+    Root(u64),
+    // only referenced through parent blocks, e.g. by a subvolume and its snapshots
+    Shared,
+}
+
+// Owner of the tree block containing `logical`, None if no tree block is allocated there.
+// The extent item is keyed (bytenr, METADATA_ITEM, level) with skinny metadata and
+// (bytenr, EXTENT_ITEM, nodesize) without, either one is followed by its backrefs.
+pub fn find_tree_block_owner(fd: i32, logical: u64, nodesize: u32) -> Result<Option<TreeBlockOwner>> {
+    let mut block: Option<u64> = None;
+    let mut res: Option<TreeBlockOwner> = None;
+    let range = SearchKey::new(logical.saturating_sub(nodesize as u64 - 1), u8::MIN, u64::MIN)..=SearchKey::new(logical, u8::MAX, u64::MAX);
+    tree_search_cb(fd, BTRFS_EXTENT_TREE_OBJECTID as u64, range, |sh, data| {
+        match sh.type_ {
+            // items come in key order, the last block starts closest to logical
+            BTRFS_METADATA_ITEM_KEY | BTRFS_EXTENT_ITEM_KEY if data.len() >= std::mem::size_of::<btrfs_extent_item>() => {
+                let item = unsafe {
+                    std::ptr::read_unaligned(data.as_ptr() as *const btrfs_extent_item)
+                };
+                let length = if sh.type_ == BTRFS_EXTENT_ITEM_KEY { sh.offset } else { nodesize as u64 };
+                if item.flags & BTRFS_EXTENT_FLAG_TREE_BLOCK as u64 == 0 || sh.objectid + length <= logical {
+                    block = None;
+                    res = None;
+                    return;
+                }
+                block = Some(sh.objectid);
+                let mut pos = std::mem::size_of::<btrfs_extent_item>();
+                if sh.type_ == BTRFS_EXTENT_ITEM_KEY {
+                    pos += std::mem::size_of::<btrfs_tree_block_info>();
+                }
+                res = data.get(pos..pos + std::mem::size_of::<btrfs_extent_inline_ref>()).and_then(|inline_ref| {
+                    let inline_ref = unsafe {
+                        std::ptr::read_unaligned(inline_ref.as_ptr() as *const btrfs_extent_inline_ref)
+                    };
+                    inline_ref_owner(inline_ref.type_ as u32, inline_ref.offset)
+                });
+            },
+            // backrefs that didn't fit inline
+            BTRFS_TREE_BLOCK_REF_KEY | BTRFS_SHARED_BLOCK_REF_KEY if res.is_none() && block == Some(sh.objectid) => {
+                res = inline_ref_owner(sh.type_, sh.offset);
+            },
+            _ => {}
+        };
+    })?;
+    // a block without any backref still belongs to some tree
+    Ok(block.map(|_| res.unwrap_or(TreeBlockOwner::Shared)))
+}
+
+fn inline_ref_owner(typ: u32, offset: u64) -> Option<TreeBlockOwner> {
+    match typ {
+        BTRFS_TREE_BLOCK_REF_KEY => Some(TreeBlockOwner::Root(offset)),
+        BTRFS_SHARED_BLOCK_REF_KEY => Some(TreeBlockOwner::Shared),
+        _ => None,
+    }
+}
+
+// Every commit updates the root items of the trees it touched, the newest one is the
+// generation of the last committed transaction.
+pub fn current_generation(fd: i32) -> Result<u64> {
+    let mut res = 0;
+    tree_search_cb(fd, BTRFS_ROOT_TREE_OBJECTID as u64, SearchKey::ALL, |sh, data| {
+        if sh.type_ == BTRFS_ROOT_ITEM_KEY {
+            // old root items are shorter than btrfs_root_item, generation right after the inode is always there
+            let offset = std::mem::size_of::<btrfs_inode_item>();
+            if let Some(generation) = data.get(offset..offset + 8) {
+                res = res.max(u64::from_le_bytes(generation.try_into().unwrap()));
+            }
+        }
+
+    })?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ioctl_error_explains_common_errors() {
+        let message = |err| ioctl_error("TREE_SEARCH_V2", err).to_string();
+        assert!(message(nix::Error::EROFS).contains("read-only or frozen"));
+        assert!(message(nix::Error::ENOTTY).contains("not on a btrfs"));
+        assert!(message(nix::Error::EPERM).contains("run as root"));
+        assert!(message(nix::Error::EACCES).contains("run as root"));
+        assert!(message(nix::Error::EPERM).starts_with("TREE_SEARCH_V2: "));
+        assert_eq!(message(nix::Error::ENOENT), format!("TREE_SEARCH_V2: {}", nix::Error::ENOENT));
+    }
+
+    #[test]
+    fn search_key_next_increments_offset() {
+        assert_eq!(SearchKey::new(256, 1, 0).next(), Some(SearchKey::new(256, 1, 1)));
+    }
+
+    #[test]
+    fn search_key_next_carries_into_type() {
+        assert_eq!(SearchKey::new(256, 1, u64::MAX).next(), Some(SearchKey::new(256, 2, 0)));
+    }
+
+    #[test]
+    fn search_key_next_carries_into_objectid() {
+        assert_eq!(SearchKey::new(256, u8::MAX, u64::MAX).next(), Some(SearchKey::new(257, 0, 0)));
+    }
+
+    #[test]
+    fn search_key_next_below_max() {
+        assert_eq!(SearchKey::new(u64::MAX, u8::MAX - 1, u64::MAX).next(), Some(SearchKey::new(u64::MAX, u8::MAX, 0)));
+        assert_eq!(SearchKey::new(u64::MAX, u8::MAX, u64::MAX - 1).next(), Some(SearchKey::MAX));
+    }
+
+    #[test]
+    fn search_key_next_of_max_is_none() {
+        assert_eq!(SearchKey::MAX.next(), None);
+    }
+
+    #[test]
+    fn block_group_names() {
+        let flags = (BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP) as u64;
+        assert_eq!((block_group_type_name(flags), profile_name(flags)), ("METADATA", "dup"));
+        let flags = BTRFS_BLOCK_GROUP_DATA as u64;
+        assert_eq!((block_group_type_name(flags), profile_name(flags)), ("DATA", "single"));
+        let flags = (BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID10) as u64;
+        assert_eq!((block_group_type_name(flags), profile_name(flags)), ("DATA+METADATA", "raid10"));
+    }
+
+    #[test]
+    fn ioctl_sizes() {
+        // logical_ino counts the container header, search_v2 only the buffer after it
+        let data = WithMemAfter::<btrfs_data_container, 4096>::new();
+        assert_eq!(data.extra_size(), 4096);
+        assert_eq!(data.total_size(), std::mem::size_of::<btrfs_data_container>() + 4096);
+        assert_eq!(data.ioctl_size(), data.total_size());
+        let search = WithMemAfter::<btrfs_ioctl_search_args_v2, 1024>::new();
+        assert_eq!(search.extra_size(), 1024);
+        assert_eq!(search.total_size(), std::mem::size_of::<btrfs_ioctl_search_args_v2>() + 1024);
+        assert_eq!(search.ioctl_size(), 1024);
+    }
+
+    #[test]
+    fn ioctl_sizes_on_heap() {
+        // a reused buffer is resized and its header zeroed
+        let mut buf = vec![u64::MAX; 10000];
+        buf.truncate(3);
+        let search = WithMemAfterOnHeap::<btrfs_ioctl_search_args_v2>::from_buf(buf, 1000);
+        assert_eq!(search.extra_size(), 1000);
+        assert_eq!(search.extra().len(), 1000);
+        assert_eq!(search.total_size(), std::mem::size_of::<btrfs_ioctl_search_args_v2>() + 1000);
+        assert_eq!(search.ioctl_size(), 1000);
+        assert_eq!((search.key.tree_id, search.buf_size), (0, 0));
+        let data = WithMemAfterOnHeap::<btrfs_data_container>::from_buf(Vec::new(), 4096);
+        assert_eq!(data.ioctl_size(), std::mem::size_of::<btrfs_data_container>() + 4096);
+    }
+
+    #[test]
+    fn extra_region_on_heap_is_writable() {
+        // an odd size ends in the middle of the last u64, also fine under Miri
+        let mut search = WithMemAfterOnHeap::<btrfs_ioctl_search_args_v2>::from_buf(Vec::new(), 1001);
+        for (i, b) in search.extra_mut().iter_mut().enumerate() {
+            *b = i as u8 | 1;
+        }
+        assert_eq!((search.key.tree_id, search.key.nr_items, search.buf_size), (0, 0, 0));
+        assert!(search.extra().iter().enumerate().all(|(i, &b)| b == i as u8 | 1));
+        assert_eq!(search.extra().len(), 1001);
+    }
+
+    // Scans the extent tree of the filesystem at $BTDU_BENCH_PATH with different search buffer
+    // sizes, then times the lookups done for every sample. Needs root:
+    // BTDU_BENCH_PATH=/mnt cargo test --release bench_search_buf_size -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_search_buf_size() {
+        let path = env::var("BTDU_BENCH_PATH").expect("BTDU_BENCH_PATH is not set");
+        let fd = fcntl::open(path.as_str(), OFlag::O_RDONLY, Mode::empty()).unwrap();
+        for buf_size in [16 * 1024, 64 * 1024, 256 * 1024] {
+            let start = std::time::Instant::now();
+            let mut items = 0;
+            for item in tree_search_with_buf_size(fd, BTRFS_EXTENT_TREE_OBJECTID as u64, SearchKey::ALL, buf_size) {
+                item.unwrap();
+                items += 1;
+            }
+            println!("buf_size={} items={} elapsed={:?}", buf_size, items, start.elapsed());
+        }
+        let lookups = 10000;
+        let start = std::time::Instant::now();
+        for _ in 0..lookups {
+            find_inode_item(fd, BTRFS_FS_TREE_OBJECTID as u64, BTRFS_FIRST_FREE_OBJECTID as u64).unwrap();
+        }
+        println!("lookups={} per_lookup={:?}", lookups, start.elapsed() / lookups);
+    }
+
+    #[test]
+    fn read_item_rejects_short_items() {
+        let sh = btrfs_ioctl_search_header { transid: 0, objectid: 256, offset: 5, type_: BTRFS_ROOT_BACKREF_KEY, len: 4 };
+        let err = read_item::<u64>(&sh, &[0; 4]).unwrap_err();
+        assert_eq!((err.objectid, err.offset, err.len, err.expected), (256, 5, 4, 8));
+        assert_eq!(read_item::<u32>(&sh, &[1, 0, 0, 0]).unwrap(), 1);
+        assert_eq!(item_name(&sh, b"xxname", 2, 4).unwrap(), b"name");
+        assert!(item_name(&sh, b"xxname", 2, 5).is_err());
+    }
+
+    // On-disk bytes of an item struct, all of them are packed
+    fn item_bytes<T>(item: &T) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(item as *const T as *const u8, std::mem::size_of::<T>()) }.to_vec()
+    }
+
+    fn qgroup_items(status_flags: u32, root_id: u64, rfer: u64, excl: u64) -> Vec<(u64, btrfs_ioctl_search_header, Vec<u8>)> {
+        let quota_tree = BTRFS_QUOTA_TREE_OBJECTID as u64;
+        let mut status: btrfs_qgroup_status_item = unsafe { std::mem::zeroed() };
+        status.flags = (BTRFS_QGROUP_STATUS_FLAG_ON | status_flags) as u64;
+        let mut info: btrfs_qgroup_info_item = unsafe { std::mem::zeroed() };
+        info.rfer = rfer;
+        info.excl = excl;
+        vec![
+            canned_item(quota_tree, 0, BTRFS_QGROUP_STATUS_KEY, 0, item_bytes(&status)),
+            canned_item(quota_tree, 0, BTRFS_QGROUP_INFO_KEY, 5, item_bytes(&info)),
+            canned_item(quota_tree, 0, BTRFS_QGROUP_INFO_KEY, root_id, item_bytes(&info)),
+        ]
+    }
+
+    fn file_extent_item(root_id: u64, inum: u64, offset: u64, typ: u32, disk_bytenr: u64, disk_num_bytes: u64) -> (u64, btrfs_ioctl_search_header, Vec<u8>) {
+        let mut extent: btrfs_file_extent_item = unsafe { std::mem::zeroed() };
+        extent.type_ = typ as u8;
+        extent.disk_bytenr = disk_bytenr;
+        extent.disk_num_bytes = disk_num_bytes;
+        canned_item(root_id, inum, BTRFS_EXTENT_DATA_KEY, offset, item_bytes(&extent))
+    }
+
+    #[test]
+    fn subvol_size_from_qgroup_or_extents() {
+        let root_id = 256;
+        let mut items = vec![
+            file_extent_item(root_id, 257, 0, BTRFS_FILE_EXTENT_REG, 1 << 20, 4096),
+            // a reflink of the same extent is counted once
+            file_extent_item(root_id, 257, 4096, BTRFS_FILE_EXTENT_REG, 1 << 20, 4096),
+            // a hole and inline data take no extent
+            file_extent_item(root_id, 257, 8192, BTRFS_FILE_EXTENT_REG, 0, 0),
+            file_extent_item(root_id, 258, 0, BTRFS_FILE_EXTENT_INLINE, 0, 0),
+            file_extent_item(root_id, 259, 0, BTRFS_FILE_EXTENT_PREALLOC, 2 << 20, 8192),
+            // extents of other subvolumes don't count
+            file_extent_item(5, 257, 0, BTRFS_FILE_EXTENT_REG, 3 << 20, 4096),
+        ];
+        let size = |items: &[(u64, btrfs_ioctl_search_header, Vec<u8>)]| {
+            subvol_size_from(&|tree_id, range, cb| search_canned(items, tree_id, range, cb), root_id).unwrap()
+        };
+
+        // without quotas the extents are summed up
+        let size_without_quotas = size(&items);
+        assert_eq!((size_without_quotas.referenced, size_without_quotas.exclusive), (12288, None));
+
+        let mut with_qgroups = qgroup_items(0, root_id, 1 << 30, 1 << 20);
+        with_qgroups.extend(items.iter().cloned());
+        let size_from_qgroup = size(&with_qgroups);
+        assert_eq!((size_from_qgroup.referenced, size_from_qgroup.exclusive), (1 << 30, Some(1 << 20)));
+
+        // numbers that are being rescanned are not trusted
+        let mut rescanning = qgroup_items(BTRFS_QGROUP_STATUS_FLAG_RESCAN, root_id, 1 << 30, 1 << 20);
+        rescanning.append(&mut items);
+        assert_eq!(size(&rescanning).referenced, 12288);
+    }
+
+    // Dir item of the root tree directory pointing at `objectid`, followed by its name
+    fn root_dir_item(hash: u64, name: &str, objectid: u64) -> (u64, btrfs_ioctl_search_header, Vec<u8>) {
+        let mut dir_item: btrfs_dir_item = unsafe { std::mem::zeroed() };
+        dir_item.location.objectid = objectid;
+        dir_item.location.type_ = BTRFS_ROOT_ITEM_KEY as u8;
+        dir_item.name_len = name.len() as u16;
+        let mut data = item_bytes(&dir_item);
+        data.extend_from_slice(name.as_bytes());
+        canned_item(BTRFS_ROOT_TREE_OBJECTID as u64, BTRFS_ROOT_TREE_DIR_OBJECTID as u64, BTRFS_DIR_ITEM_KEY, hash, data)
+    }
+
+    #[test]
+    fn default_subvol_from_dir_item() {
+        let find = |items: &[(u64, btrfs_ioctl_search_header, Vec<u8>)]| find_default_subvol_from(&|tree_id, range, cb| search_canned(items, tree_id, range, cb));
+        let items = [root_dir_item(1, "other", 300), root_dir_item(2, "default", 257)];
+        assert_eq!(find(&items).unwrap(), Some(257));
+        assert_eq!(find(&items[..1]).unwrap(), None);
+        assert_eq!(find(&[]).unwrap(), None);
+
+        // a name cut short is an error, not a different name
+        let (tree_id, mut sh, mut data) = root_dir_item(2, "default", 257);
+        data.truncate(data.len() - 1);
+        sh.len = data.len() as u32;
+        let err = find(&[(tree_id, sh, data)]).unwrap_err();
+        assert!(err.is::<MalformedItem>(), "{}", err);
+    }
+
+    #[test]
+    fn chunk_physical_lengths() {
+        let gib = 1u64 << 30;
+        // (profile, num_stripes, sub_stripes, physical size of a 12 GiB chunk)
+        let chunks = [
+            (0, 1, 1, 12 * gib),
+            (BTRFS_BLOCK_GROUP_DUP, 2, 1, 24 * gib),
+            (BTRFS_BLOCK_GROUP_RAID0, 4, 1, 12 * gib),
+            (BTRFS_BLOCK_GROUP_RAID1, 2, 1, 24 * gib),
+            (BTRFS_BLOCK_GROUP_RAID1C3, 3, 1, 36 * gib),
+            (BTRFS_BLOCK_GROUP_RAID1C4, 4, 1, 48 * gib),
+            (BTRFS_BLOCK_GROUP_RAID10, 4, 2, 24 * gib),
+            (BTRFS_BLOCK_GROUP_RAID10, 6, 2, 24 * gib),
+            // one stripe of parity
+            (BTRFS_BLOCK_GROUP_RAID5, 3, 1, 18 * gib),
+            (BTRFS_BLOCK_GROUP_RAID5, 5, 1, 15 * gib),
+            // two stripes of parity
+            (BTRFS_BLOCK_GROUP_RAID6, 4, 1, 24 * gib),
+            (BTRFS_BLOCK_GROUP_RAID6, 6, 1, 18 * gib),
+        ];
+        for (profile, num_stripes, sub_stripes, physical) in chunks {
+            let mut chunk: btrfs_chunk = unsafe { std::mem::zeroed() };
+            chunk.length = 12 * gib;
+            chunk.type_ = (BTRFS_BLOCK_GROUP_DATA | profile) as u64;
+            chunk.num_stripes = num_stripes;
+            chunk.sub_stripes = sub_stripes;
+            assert_eq!(chunk_physical_length(&chunk), physical, "{} with {} stripes", profile_name(chunk.type_), num_stripes);
+        }
+    }
+
+    #[test]
+    fn profile_names() {
+        let profiles = [
+            (0, "single"),
+            (BTRFS_BLOCK_GROUP_DUP, "dup"),
+            (BTRFS_BLOCK_GROUP_RAID0, "raid0"),
+            (BTRFS_BLOCK_GROUP_RAID1, "raid1"),
+            (BTRFS_BLOCK_GROUP_RAID1C3, "raid1c3"),
+            (BTRFS_BLOCK_GROUP_RAID1C4, "raid1c4"),
+            (BTRFS_BLOCK_GROUP_RAID10, "raid10"),
+            (BTRFS_BLOCK_GROUP_RAID5, "raid5"),
+            (BTRFS_BLOCK_GROUP_RAID6, "raid6"),
+            (BTRFS_BLOCK_GROUP_RAID1 | BTRFS_BLOCK_GROUP_DUP, "unknown"),
+        ];
+        for (profile, name) in profiles {
+            assert_eq!(profile_name((BTRFS_BLOCK_GROUP_DATA | profile) as u64), name);
+            assert_eq!(profile_name((BTRFS_BLOCK_GROUP_METADATA | profile) as u64), name);
+        }
+    }
+}
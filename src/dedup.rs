@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::btrfs;
+
+pub struct DedupEstimate {
+    pub checksummed_bytes: u64,
+    pub duplicate_bytes: u64,
+}
+
+// Counts sectors and how many of them repeat a checksum seen before.
+// Checksums are keyed by their first 8 bytes: that is all of crc32c and xxhash,
+// and any prefix of sha256 or blake2b is as good as a hash of its own.
+struct DedupCounter {
+    csum_size: usize,
+    seen: HashSet<u64>,
+    sectors: u64,
+    duplicate_sectors: u64,
+}
+
+impl DedupCounter {
+    fn new(csum_size: usize) -> Self {
+        Self {
+            csum_size: csum_size.max(1),
+            seen: HashSet::new(),
+            sectors: 0,
+            duplicate_sectors: 0,
+        }
+    }
+
+    // Data of one csum item, a checksum per sector
+    fn add_item(&mut self, data: &[u8]) {
+        for csum in data.chunks_exact(self.csum_size) {
+            let mut key = [0u8; 8];
+            let n = csum.len().min(8);
+            key[..n].copy_from_slice(&csum[..n]);
+            self.sectors += 1;
+            if !self.seen.insert(u64::from_le_bytes(key)) {
+                self.duplicate_sectors += 1;
+            }
+        }
+    }
+}
+
+// Heuristic: every data sector with checksums enabled has one entry in the csum tree,
+// so sectors sharing a checksum are treated as duplicates of each other.
+// Checksum collisions (likely with 4-byte crc32c on large filesystems) inflate the estimate,
+// and nodatasum/nocow data is invisible to it.
+// Memory use is 8 bytes (plus hash set overhead) per distinct checksum.
+pub fn estimate(fd: i32, fs_info: &btrfs::FsInfo) -> Result<DedupEstimate> {
+    let mut counter = DedupCounter::new(fs_info.csum_size as usize);
+
+    let objectid = btrfs::BTRFS_EXTENT_CSUM_OBJECTID as u64;
+    btrfs::tree_search_cb(fd, btrfs::BTRFS_CSUM_TREE_OBJECTID as u64, btrfs::SearchKey::range_fixed_id_type(objectid, btrfs::BTRFS_EXTENT_CSUM_KEY as u8), |sh, data| {
+        if sh.type_ == btrfs::BTRFS_EXTENT_CSUM_KEY {
+            counter.add_item(data);
+        }
+    })?;
+
+    Ok(DedupEstimate {
+        checksummed_bytes: counter.sectors * fs_info.sectorsize as u64,
+        duplicate_bytes: counter.duplicate_sectors * fs_info.sectorsize as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_csum_counts_as_duplicate() {
+        // crc32c: 4 sectors, the third repeats the first
+        let mut counter = DedupCounter::new(4);
+        counter.add_item(&[1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0]);
+        // the same checksum in another item counts too
+        counter.add_item(&[2, 0, 0, 0]);
+        assert_eq!((counter.sectors, counter.duplicate_sectors), (4, 2));
+
+        // sha256: only a repeat of all leading 8 bytes is a duplicate
+        let mut counter = DedupCounter::new(32);
+        let mut data = vec![7u8; 32 * 3];
+        data[32] = 8;
+        counter.add_item(&data);
+        assert_eq!((counter.sectors, counter.duplicate_sectors), (3, 1));
+    }
+}
@@ -2,17 +2,149 @@
 #![feature(hash_raw_entry)]
 #![feature(stdio_locked)]
 
-use std::{collections::{HashMap, HashSet}, env, hash::{BuildHasher, Hasher}, alloc::Layout, ops::{Deref, DerefMut, Range, RangeInclusive}, ffi::{CStr, CString}, fmt, io::Write, rc::Rc};
+use std::{borrow::Cow, cell::RefCell, collections::{hash_map::Entry, BTreeMap, HashMap, HashSet}, env, hash::{BuildHasher, Hasher}, alloc::Layout, ops::{Deref, DerefMut, Range, RangeInclusive}, ffi::{CStr, CString}, fmt, io::Write, rc::Rc, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
 
-use nix::{fcntl::{self, OFlag}, libc::{self, c_char}, sys::stat::Mode};
+use nix::{fcntl::{self, OFlag}, libc::{self, c_char}, sys::{signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal}, stat::Mode}};
 use nix::NixPath;
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use clap::{ArgEnum, Parser};
+use crossterm::style::{Color, Stylize};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-use rand::distributions::{Distribution, Uniform};
+use rand::{distributions::{Distribution, Uniform}, rngs::StdRng, Rng, SeedableRng};
+use tracing::{debug, trace, warn};
+use tracing_subscriber::{filter::LevelFilter, prelude::*, EnvFilter};
 
 mod btrfs;
+mod dedup;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod tui;
 
+/// Top-level node every sample is filed under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SampleCategory {
+    Data,
+    Metadata,
+    System,
+    // unallocated space within chunks, with --free-space
+    Free,
+    Error,
+}
+
+impl SampleCategory {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SampleCategory::Data => "DATA",
+            SampleCategory::Metadata => "METADATA",
+            SampleCategory::System => "SYSTEM",
+            SampleCategory::Free => "FREE",
+            SampleCategory::Error => "ERROR",
+        }
+    }
+}
+
+impl SampleCategory {
+    const ALL: [SampleCategory; 5] = [SampleCategory::Data, SampleCategory::Metadata, SampleCategory::System, SampleCategory::Free, SampleCategory::Error];
+
+    // Category of a top-level node, which --profiles names e.g. DATA[raid1]
+    fn from_label(label: &str) -> Option<SampleCategory> {
+        let name = label.split_once('[').map_or(label, |(name, _)| name);
+        SampleCategory::ALL.into_iter().find(|c| c.as_str() == name)
+    }
+
+    // Name of the top-level node for samples of a chunk
+    fn label(&self, chunk_type: u64, with_profile: bool) -> Cow<'static, str> {
+        if with_profile {
+            Cow::Owned(format!("{}[{}]", self.as_str(), btrfs::profile_name(chunk_type)))
+        } else {
+            Cow::Borrowed(self.as_str())
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            SampleCategory::Data => Color::Blue,
+            SampleCategory::Metadata => Color::Magenta,
+            SampleCategory::System => Color::Cyan,
+            SampleCategory::Free => Color::DarkGrey,
+            SampleCategory::Error => Color::Red,
+        }
+    }
+}
+
+impl fmt::Display for SampleCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct PrintOptions {
+    min_disk_fraction: Option<f64>,
+    collapse_single_child: bool,
+    /// Paths (split into segments) that are shown regardless of min_disk_fraction
+    pins: Vec<Vec<String>>,
+    /// Total row width to fit into; long names are shortened in the middle
+    max_width: Option<usize>,
+    /// Number of tree levels to print
+    max_depth: Option<usize>,
+    /// Color categories and large percentages with ANSI escapes
+    color: bool,
+}
+
+// What print_internal needs besides the node, the same for every row of one level
+#[derive(Clone, Copy)]
+struct PrintContext<'a> {
+    opts: &'a PrintOptions,
+    total_samples: usize,
+    total_length: u64,
+    // pinned paths below the level being printed, relative to it
+    pins: &'a [&'a [String]],
+    path_width: Option<usize>,
+    count_width: usize,
+    // path of the level being printed, only kept track of for `shown`
+    path: &'a str,
+    shown: Option<&'a RefCell<ShownPaths>>,
+}
+
+// Paths --follow showed in the previous redraw. A shown path stays until it drops below
+// HIDE_FACTOR times --min-pct and a hidden one only appears at SHOW_FACTOR times it, so
+// paths close to --min-pct don't flicker in and out between redraws.
+#[derive(Default)]
+struct ShownPaths {
+    // None before the first redraw, which uses --min-pct as it is
+    previous: Option<HashSet<String>>,
+    current: HashSet<String>,
+}
+
+impl ShownPaths {
+    const HIDE_FACTOR: f64 = 0.8;
+    const SHOW_FACTOR: f64 = 1.2;
+
+    fn min_disk_fraction(&self, path: &str, min_disk_fraction: f64) -> f64 {
+        match &self.previous {
+            Some(previous) if previous.contains(path) => min_disk_fraction * Self::HIDE_FACTOR,
+            Some(_) => min_disk_fraction * Self::SHOW_FACTOR,
+            None => min_disk_fraction,
+        }
+    }
+
+    // The paths shown by this redraw become the previous ones of the next
+    fn finish_redraw(&mut self) {
+        self.previous = Some(std::mem::take(&mut self.current));
+    }
+}
+
+// Paths kept by the path cache, a few MiB with typical path lengths
+const PATH_CACHE_CAPACITY: usize = 100_000;
+
+// Name of the node collecting what --max-nodes and --prune folded away. It is printed in the
+// same row as the children hidden by --min-pct.
+const OTHER_NODE: &str = "<others>";
+
+#[derive(Clone, Serialize, Deserialize)]
 struct SampleTree {
     total: usize,
     children: HashMap<String, SampleTree>,
@@ -32,201 +164,2887 @@ impl SampleTree {
         }
     }
 
-    fn add<'a>(&mut self, mut path: impl Iterator<Item=&'a str>) {
-        self.total += 1;
+    fn add<'a>(&mut self, path: impl Iterator<Item=&'a str>) {
+        self.add_samples(path, 1);
+    }
+
+    // Adds n samples at once. `total` is the number of samples at or below a node either way,
+    // so trees built from single samples and from counts (e.g. a merged profile) mix freely.
+    fn add_samples<'a>(&mut self, mut path: impl Iterator<Item=&'a str>, n: usize) {
+        // no samples, no empty nodes
+        if n == 0 {
+            return;
+        }
+        self.total += n;
         match path.next() {
             Some(p) => {
-                self.children.raw_entry_mut().from_key(p).or_insert_with(|| (p.to_owned(), SampleTree::new())).1.add(path);
+                self.children.raw_entry_mut().from_key(p).or_insert_with(|| (p.to_owned(), SampleTree::new())).1.add_samples(path, n);
             },
             None => {},
         }   
     }
 
+    // Adds the samples of another tree, each counted as `scale` samples of this one, and returns
+    // how many were added. Only the samples ending at a node are rounded, totals are the sums of
+    // those, so a node never ends up with less than its children.
+    fn merge(&mut self, other: SampleTree, scale: f64) -> usize {
+        let own = other.total.saturating_sub(other.children.values().map(|c| c.total).sum::<usize>());
+        let mut added = (own as f64 * scale).round() as usize;
+        for (k, child) in other.children {
+            added += self.children.entry(k).or_default().merge(child, scale);
+        }
+        self.total += added;
+        added
+    }
+
+    // Estimated size of what was sampled at or below this node
+    fn total_bytes(&self, bytes_per_sample: f64) -> u64 {
+        (self.total as f64 * bytes_per_sample) as u64
+    }
+
  
 
-    fn print_internal<W: fmt::Write>(&self, w: &mut W, total_samples: usize, total_length:u64, min_disk_fraction: Option<f64>, depth: usize) -> fmt::Result {
+    fn print_internal<W: fmt::Write>(&self, w: &mut W, ctx: &PrintContext, depth: usize) -> fmt::Result {
+        let PrintContext { opts, total_samples, total_length, path_width, count_width, .. } = *ctx;
+        let empty = SampleTree::new();
         let mut c: Vec<_> = self.children.iter().collect();
-        c.sort_by_key(|(_,v)| std::cmp::Reverse(v.total));
+        // pinned paths that have no samples (yet) are shown as empty nodes
+        for pin in ctx.pins {
+            if let Some(p) = pin.first() {
+                if !c.iter().any(|(k,_)| *k == p) {
+                    c.push((p, &empty));
+                }
+            }
+        }
+        // ties are broken by name so the output doesn't depend on HashMap iteration order
+        c.sort_by(|(ka,va), (kb,vb)| vb.total.cmp(&va.total).then_with(|| ka.cmp(kb)));
+        let mut others_total = 0;
+        let mut others_count = 0;
         for (k,v) in &c {
             let disk_fraction = (v.total as f64) / (total_samples as f64);
-            let disk_bytes = (total_length as f64 * disk_fraction) as u64;
+            let disk_bytes = v.total_bytes(total_length as f64 / total_samples as f64);
+            let child_pins: Vec<&[String]> = ctx.pins.iter().filter(|p| p.first() == Some(k)).map(|p| &p[1..]).collect();
 
+            if *k == OTHER_NODE {
+                others_total += v.total;
+                continue;
+            }
+            let mut node_path = ctx.shown.map(|_| format!("{}/{}", ctx.path, k));
+            let min_disk_fraction = match (opts.min_disk_fraction, ctx.shown, &node_path) {
+                (Some(min_disk_fraction), Some(shown), Some(node_path)) => Some(shown.borrow().min_disk_fraction(node_path, min_disk_fraction)),
+                (min_disk_fraction, _, _) => min_disk_fraction,
+            };
             match min_disk_fraction {
-                Some(min_disk_fraction) if disk_fraction < min_disk_fraction => continue,
+                Some(min_disk_fraction) if disk_fraction < min_disk_fraction && child_pins.is_empty() => {
+                    others_total += v.total;
+                    others_count += 1;
+                    continue
+                },
                 _ => {},
             }
+            if let (Some(shown), Some(node_path)) = (ctx.shown, &node_path) {
+                shown.borrow_mut().current.insert(node_path.clone());
+            }
 
-            let path = { 
+            let mut v = *v;
+            let path = {
                 let mut path =  String::new();
                 for i in 0..depth {
                     path.push_str(" ");
                 }
                 path.push('/');
                 path.push_str(k);
+                // pinned subtrees are not collapsed so that missing pinned children can still be shown
+                if opts.collapse_single_child && child_pins.is_empty() {
+                    // a chain link carries no branching information unless samples end at it
+                    while let Some((ck, cv)) = v.single_child() {
+                        if cv.total != v.total {
+                            break;
+                        }
+                        path.push('/');
+                        path.push_str(ck);
+                        if let Some(node_path) = &mut node_path {
+                            node_path.push('/');
+                            node_path.push_str(ck);
+                        }
+                        v = cv;
+                    }
+                }
+                // nodes with children end with a slash like directories in ls -F, files don't. It is
+                // added after truncating, which keeps the name after the last slash.
+                let slash = if v.children.is_empty() { "" } else { "/" };
+                let mut path = match path_width {
+                    Some(path_width) => truncate_middle(&path, path_width - slash.len()),
+                    None => path,
+                };
+                path.push_str(slash);
                 path
             };
 
-            writeln!(w, "{:60} {:>8} {:>4.1}% {:>16}", path, v.total,  disk_fraction * 100.0, bytesize::to_string(disk_bytes, true))?;
-            v.print_internal(w, total_samples, total_length, min_disk_fraction, depth+1)?;
+            let width = path_width.unwrap_or(60);
+            // escapes are added after padding so they don't count towards the width
+            let mut path = pad_width(&path, width);
+            let mut percentage = format!("{:>5.1}%", disk_fraction * 100.0);
+            if opts.color {
+                if let Some(category) = SampleCategory::from_label(k).filter(|_| depth == 0) {
+                    path = path.with(category.color()).to_string();
+                }
+                if disk_fraction >= 0.25 {
+                    percentage = percentage.with(Color::Red).to_string();
+                } else if disk_fraction >= 0.05 {
+                    percentage = percentage.with(Color::Yellow).to_string();
+                }
+            }
+            writeln!(w, "{} {:>count_width$} {} {:>16}", path, format_count(v.total), percentage, bytesize::to_string(disk_bytes, true))?;
+            // deeper samples are already part of this row's total
+            if opts.max_depth.is_none_or(|max_depth| depth + 1 < max_depth) {
+                v.print_internal(w, &PrintContext { pins: &child_pins, path: node_path.as_deref().unwrap_or_default(), ..*ctx }, depth+1)?;
+            }
+        }
+
+        // hidden children are folded into one row so that every level still sums up to its parent
+        if others_total > 0 || others_count > 0 {
+            let disk_fraction = (others_total as f64) / (total_samples as f64);
+            let disk_bytes = (total_length as f64 * disk_fraction) as u64;
+            let path = if others_count > 0 {
+                format!("{}<others: {} items>", " ".repeat(depth), others_count)
+            } else {
+                format!("{}{}", " ".repeat(depth), OTHER_NODE)
+            };
+            let width = path_width.unwrap_or(60);
+            writeln!(w, "{:width$} {:>count_width$} {:>5.1}% {:>16}", path, format_count(others_total),  disk_fraction * 100.0, bytesize::to_string(disk_bytes, true))?;
         }
 
         Ok(())
     }
 
-    fn print<W: fmt::Write>(&self, w: &mut W, total_samples: usize, total_length:u64, min_disk_fraction: Option<f64>) -> fmt::Result {
-        self.print_internal(w, total_samples, total_length, min_disk_fraction, 0)
+    fn diff(&self, baseline: &SampleTree) -> SampleTreeDiff {
+        let empty = SampleTree::new();
+        let mut children = HashMap::new();
+        for k in self.children.keys().chain(baseline.children.keys()) {
+            if children.contains_key(k) {
+                continue;
+            }
+            let v = self.children.get(k).unwrap_or(&empty);
+            let baseline_v = baseline.children.get(k).unwrap_or(&empty);
+            children.insert(k.clone(), v.diff(baseline_v));
+        }
+        SampleTreeDiff {
+            total: self.total,
+            baseline_total: baseline.total,
+            children,
+        }
     }
-}
 
+    // Node at `path` below this one, the node itself for an empty path
+    fn get<'a>(&self, path: impl Iterator<Item=&'a str>) -> Option<&SampleTree> {
+        let mut node = self;
+        for p in path {
+            node = node.children.get(p)?;
+        }
+        Some(node)
+    }
 
+    // Samples at or below `path`, 0 when nothing was sampled there
+    fn total_at<'a>(&self, path: impl Iterator<Item=&'a str>) -> usize {
+        self.get(path).map_or(0, |node| node.total)
+    }
 
-struct Roots {
-    fd: i32,
-    m: HashMap<u64, Rc<Vec<String>>>,
-}
+    fn node_count(&self) -> usize {
+        self.children.values().map(|c| 1 + c.node_count()).sum()
+    }
 
-impl Roots {
-    fn new(fd: i32) -> Self {
-        Self {
-            fd,
-            m: HashMap::from([(5, Rc::new(Vec::new()))]),
+    fn collect_leaves(&self, path: &mut Vec<String>, leaves: &mut Vec<(usize, Vec<String>)>) {
+        for (k, v) in &self.children {
+            path.push(k.clone());
+            if v.children.is_empty() {
+                leaves.push((v.total, path.clone()));
+            } else {
+                v.collect_leaves(path, leaves);
+            }
+            path.pop();
         }
     }
-    fn get_root(&mut self, root_id: u64) -> Rc<Vec<String>> {
-        match self.m.get(&root_id) {
-            Some(path) => Rc::clone(path),
-            None => {
-                let (name, parent_id) = btrfs::find_root_backref(self.fd, root_id).unwrap();
-                let mut path = Vec::clone(&self.get_root(parent_id)); 
-                path.push(name);
-                let path_rc = Rc::new(path);
-                self.m.insert(root_id, path_rc.clone());
-                path_rc
+
+    // Removes a leaf and adds its samples to the "<others>" sibling.
+    // The parent total already includes them, so totals stay consistent.
+    fn fold_leaf(&mut self, path: &[String]) {
+        match path {
+            [] => {},
+            [name] => {
+                if let Some(leaf) = self.children.remove(name) {
+                    if name != OTHER_NODE {
+                        self.children.entry(OTHER_NODE.to_owned()).or_default().total += leaf.total;
+
+                    }
+                }
+            },
+            [name, rest @ ..] => {
+                if let Some(child) = self.children.get_mut(name) {
+                    child.fold_leaf(rest);
+                }
             },
         }
     }
+
+    // Folds the smallest leaves until at most max_nodes nodes are left.
+    // Pinned paths and their ancestors are never folded, nor are the categories right below the root.
+    fn limit_nodes(&mut self, max_nodes: usize, pins: &[Vec<String>]) {
+        let mut count = self.node_count();
+        while count > max_nodes {
+            let mut leaves = Vec::new();
+            for (k, v) in &self.children {
+                v.collect_leaves(&mut vec![k.clone()], &mut leaves);
+            }
+            leaves.retain(|(_, path)| !pins.iter().any(|pin| pin.starts_with(path)));
+            if leaves.is_empty() {
+                break;
+            }
+            leaves.sort();
+            for (_, path) in leaves.iter().take(count - max_nodes) {
+                self.fold_leaf(path);
+            }
+            count = self.node_count();
+        }
+    }
+
+    // Drops subtrees with fewer than min_total samples and adds them to the "<others>" child,
+    // so that memory stays bounded on long runs. Pinned paths and their ancestors are kept.
+    fn prune(&mut self, min_total: usize, pins: &[&[String]]) {
+        let mut pruned = 0;
+        self.children.retain(|k, v| {
+            let keep = v.total >= min_total || k == OTHER_NODE || pins.iter().any(|p| p.first() == Some(k));
+            if !keep {
+                pruned += v.total;
+            }
+            keep
+        });
+        if pruned > 0 {
+            self.children.entry(OTHER_NODE.to_owned()).or_default().total += pruned;
+        }
+        for (k, v) in &mut self.children {
+            let child_pins: Vec<&[String]> = pins.iter().filter(|p| p.first() == Some(k)).map(|p| &p[1..]).collect();
+            v.prune(min_total, &child_pins);
+        }
+    }
+
+    fn single_child(&self) -> Option<(&String, &SampleTree)> {
+        if self.children.len() == 1 {
+            self.children.iter().next()
+        } else {
+            None
+        }
+    }
+
+    // The tree as --json writes it, children sorted like in print and the ones below
+    // min_disk_fraction left out.
+    fn json_node<'a>(&'a self, name: &'a str, total_samples: usize, total_length: u64, min_disk_fraction: Option<f64>) -> JsonNode<'a> {
+        let mut c: Vec<_> = self.children.iter().collect();
+        c.sort_by(|(ka,va), (kb,vb)| vb.total.cmp(&va.total).then_with(|| ka.cmp(kb)));
+        let children = c.into_iter()
+            .filter(|(_,v)| min_disk_fraction.is_none_or(|min_disk_fraction| (v.total as f64) / (total_samples as f64) >= min_disk_fraction))
+            .map(|(k,v)| v.json_node(k, total_samples, total_length, min_disk_fraction))
+            .collect();
+        JsonNode {
+            name,
+            total: self.total,
+            disk_bytes: self.total_bytes(total_length as f64 / total_samples as f64),
+            fraction: (self.total as f64) / (total_samples as f64),
+            children,
+            summary: None,
+        }
+    }
+
+    // Writes one "path,samples,fraction,disk_bytes" row per node below this one, in the order
+    // of print and leaving out the ones below min_disk_fraction. The header row is up to the caller.
+    fn write_csv<W: fmt::Write>(&self, w: &mut W, prefix: &str, total_samples: usize, total_length: u64, min_disk_fraction: Option<f64>) -> fmt::Result {
+        let mut c: Vec<_> = self.children.iter().collect();
+        c.sort_by(|(ka,va), (kb,vb)| vb.total.cmp(&va.total).then_with(|| ka.cmp(kb)));
+        for (k,v) in c {
+            let disk_fraction = (v.total as f64) / (total_samples as f64);
+            if min_disk_fraction.is_some_and(|min_disk_fraction| disk_fraction < min_disk_fraction) {
+                continue;
+            }
+            let disk_bytes = v.total_bytes(total_length as f64 / total_samples as f64);
+            let path = format!("{}/{}", prefix, k);
+            writeln!(w, "{},{},{},{}", csv_field(&path), v.total, disk_fraction, disk_bytes)?;
+            v.write_csv(w, &path, total_samples, total_length, min_disk_fraction)?;
+        }
+        Ok(())
+    }
+
+    // Full path ("/DATA/home/user") and total of every node below this one, with leaves_only
+    // just the nodes without children
+    fn flatten(&self, prefix: &str, leaves_only: bool, res: &mut Vec<(String, usize)>) {
+        for (k, v) in &self.children {
+            let path = format!("{}/{}", prefix, k);
+            if !leaves_only || v.children.is_empty() {
+                res.push((path.clone(), v.total));
+            }
+            v.flatten(&path, leaves_only, res);
+        }
+    }
+
+    // Writes "a;b;c count" lines as consumed by flamegraph.pl and inferno. Every node gets the
+    // samples that end at it, which for leaves is their total.
+    fn write_folded<W: fmt::Write>(&self, w: &mut W, prefix: &str) -> fmt::Result {
+        let self_total = self.total - self.children.values().map(|c| c.total).sum::<usize>();
+        if self_total > 0 && !prefix.is_empty() {
+            writeln!(w, "{} {}", prefix, self_total)?;
+        }
+        let mut c: Vec<_> = self.children.iter().collect();
+        c.sort_by_key(|&(k, _)| k);
+        for (k,v) in c {
+            // ';' separates frames
+            let name = k.replace(';', ":");
+            if prefix.is_empty() {
+                v.write_folded(w, &name)?;
+            } else {
+                v.write_folded(w, &format!("{};{}", prefix, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print<W: fmt::Write>(&self, w: &mut W, total_samples: usize, total_length:u64, opts: &PrintOptions) -> fmt::Result {
+        self.print_shown(w, total_samples, total_length, opts, None)
+    }
+
+    // print for redraws of --follow, which keep paths close to --min-pct as they were in the previous one
+    fn print_shown<W: fmt::Write>(&self, w: &mut W, total_samples: usize, total_length:u64, opts: &PrintOptions, shown: Option<&RefCell<ShownPaths>>) -> fmt::Result {
+        // no row can exceed the root total, so its width fits every count in the tree
+        let count_width = format_count(self.total).len();
+        let pins: Vec<&[String]> = opts.pins.iter().map(|p| p.as_slice()).collect();
+        // whatever is left after the samples, percentage and size columns
+        let path_width = opts.max_width.map(|max_width| max_width.saturating_sub(count_width + 26).max(20));
+        let ctx = PrintContext { opts, total_samples, total_length, pins: &pins, path_width, count_width, path: "", shown };
+        self.print_internal(w, &ctx, 0)?;
+        if let Some(shown) = shown {
+            shown.borrow_mut().finish_redraw();
+        }
+        Ok(())
+    }
 }
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-    /// Number of samples to take
-    #[clap(short, long, default_value_t = 100000)]
-    samples: u64,
+// Samples at each path of two trees, paths missing from one of them have 0 samples there
+struct SampleTreeDiff {
+    total: usize,
+    baseline_total: usize,
+    children: HashMap<String, SampleTreeDiff>,
+}
 
-    /// Filter tree by min disk usage percentage 0..100
-    #[clap(short, long, default_value_t = 1.0)]
-    min_pct: f64,
+impl SampleTreeDiff {
+    // Appends the change in bytes of every node below this one
+    fn collect_deltas(&self, prefix: &str, bytes_per_sample: f64, baseline_bytes_per_sample: f64, deltas: &mut Vec<(String, i64)>) {
+        for (k, v) in &self.children {
+            let path = format!("{}/{}", prefix, k);
+            let delta = v.total as f64 * bytes_per_sample - v.baseline_total as f64 * baseline_bytes_per_sample;
+            deltas.push((path.clone(), delta as i64));
+            v.collect_deltas(&path, bytes_per_sample, baseline_bytes_per_sample, deltas);
+        }
+    }
+}
 
-    /// Mounted btrfs path
-    path: String,
+// Shortens a row to `width` terminal columns by replacing the middle of the name with an ellipsis,
+// keeping the indentation, the beginning of the name and its final path component when possible.
+// Wide characters (CJK, emoji) take two columns.
+fn truncate_middle(s: &str, width: usize) -> String {
+    let len = s.width();
+    if len <= width {
+        return s.to_owned();
+    }
+    let indent = s.chars().take_while(|c| *c == ' ').count();
+    let tail_start = s.rfind('/').map_or(0, |i| s[..i].width());
+    let tail_len = len - tail_start;
+    // one column is taken by the ellipsis
+    let (head_len, tail_len) = if tail_start > indent + 1 && tail_len + indent + 2 < width {
+        (width - 1 - tail_len, tail_len)
+    } else {
+        let keep = width.saturating_sub(1);
+        (keep - keep / 2, keep / 2)
+    };
+    let mut res = take_width(s.chars(), head_len);
+    res.push('…');
+    let tail: Vec<char> = take_width(s.chars().rev(), tail_len).chars().rev().collect();
+    res.extend(tail);
+    res
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    // let args: Vec<_> = env::args().collect();
-    let fd = fcntl::open(args.path.as_str(), OFlag::O_RDONLY, Mode::empty())?;
-    // let samples = args[2].as_str().parse::<usize>()?;
-    let samples = args.samples;
+// The leading characters that fit into `width` columns
+fn take_width(chars: impl Iterator<Item = char>, width: usize) -> String {
+    let mut used = 0;
+    chars.take_while(|c| {
+        used += c.width().unwrap_or(0);
+        used <= width
+    }).collect()
+}
 
+// Pads with spaces to `width` columns, `format!("{:width$}")` counts characters instead
+fn pad_width(s: &str, width: usize) -> String {
+    format!("{}{}", s, " ".repeat(width.saturating_sub(s.width())))
+}
 
+// Width of the terminal on stdout, None when stdout is not a terminal
+fn terminal_width() -> Option<usize> {
+    if !nix::unistd::isatty(libc::STDOUT_FILENO).unwrap_or(false) {
+        return None;
+    }
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+    if res < 0 || ws.ws_col == 0 {
+        return None;
+    }
+    Some(ws.ws_col as usize)
+}
 
-    #[derive(Debug)]
-    struct ChunkInfo {
-        pos: u64,
-        chunk_offset: u64,
-        chunk_length: u64,
-        chunk_type: u64,
+// Quotes a field containing a separator, quote or line break as described in RFC 4180
+fn csv_field(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
     }
+}
 
-    let mut chunks = Vec::new();
-    let mut total_chunk_length = 0;
-    btrfs::tree_search_cb(fd, btrfs::BTRFS_CHUNK_TREE_OBJECTID as u64, btrfs::SearchKey::ALL, |sh, data| {
-        match sh.type_ {
-            btrfs::BTRFS_CHUNK_ITEM_KEY => {
-                let chunk = unsafe {
-                    &*(data.as_ptr() as *const btrfs::btrfs_chunk)
-                };
-                chunks.push(ChunkInfo{
-                    pos: total_chunk_length,
-                    chunk_offset:sh.offset, 
-                    chunk_length:chunk.length,
-                    chunk_type: chunk.type_,
-                });
-                total_chunk_length += chunk.length;
-            },
-            _ => {}
+// A path is excluded when a pattern matches it or one of its parent directories
+fn is_excluded(patterns: &[glob::Pattern], path: &[Cow<str>]) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+    let opts = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    let mut prefix = String::new();
+    for segment in path {
+        prefix.push('/');
+        prefix.push_str(segment);
+        if patterns.iter().any(|p| p.matches_with(&prefix, opts)) {
+            return true;
+        }
+    }
+    false
+}
+
+// ".mp4" for "video.mp4", dotfiles like ".bashrc" and names without a dot have none
+fn file_extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(i) if i > 0 => &name[i..],
+        _ => "<none>",
+    }
+}
+
+// Parses a --rate value, which must be a positive number of samples per second
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate: {}, expected a positive number of samples per second", s)),
+    }
+}
+
+// Parses durations like "30s", "5m", "1h30m" or "500ms", a plain number is taken as seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    if s.is_empty() {
+        return Err("empty duration".to_owned());
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n: u64 = rest[..digits].parse().map_err(|_| format!("invalid duration {:?}", s))?;
+        rest = &rest[digits..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        total += match &rest[..unit_len] {
+            "ms" => Duration::from_millis(n),
+            "s" => Duration::from_secs(n),
+            "m" => Duration::from_secs(n * 60),
+            "h" => Duration::from_secs(n * 60 * 60),
+            "d" => Duration::from_secs(n * 24 * 60 * 60),
+            _ => return Err(format!("invalid duration {:?}, units are ms, s, m, h and d", s)),
         };
-    })?;
+        rest = &rest[unit_len..];
+    }
+    Ok(total)
+}
 
-    let mut roots = Roots::new(fd);
- 
+// Parses sizes like "512K", "4M" or "1G" with binary suffixes (K is 1024), a plain number is taken as bytes
+fn parse_size(s: &str) -> Result<u64, String> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n: u64 = s[..digits].parse().map_err(|_| format!("invalid size {:?}", s))?;
+    let unit: u64 = match s[digits..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        _ => return Err(format!("invalid size {:?}, suffixes are K, M, G and T", s)),
+    };
+    match n.checked_mul(unit) {
+        Some(0) => Err("size must be positive".to_owned()),
+        Some(size) => Ok(size),
+        None => Err(format!("size {:?} is too large", s)),
+    }
+}
 
-    let uniform = Uniform::new(0, total_chunk_length);
-    let mut rng = rand::thread_rng();
+// A change in bytes like "+1.5 GiB" or "-300.0 MiB"
+fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!("{}{}", sign, bytesize::to_string(delta.unsigned_abs(), true))
+}
 
-    let mut sample_tree = SampleTree::new();
-    let mut total_samples = 0;
-    let mut start = std::time::Instant::now();
-    for _ in 0..samples {
-        let random_pos = uniform.sample(&mut rng);
-        let random_chunk = chunks.iter().find(|c| {
-            random_pos >= c.pos && random_pos < c.pos + c.chunk_length
-        }).unwrap();
+fn format_count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut res = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
 
-        total_samples += 1;
-        
-        match (random_chunk.chunk_type as u32) & btrfs::BTRFS_BLOCK_GROUP_TYPE_MASK {
-            btrfs::BTRFS_BLOCK_GROUP_DATA => {
-                let random_offset = random_chunk.chunk_offset + (random_pos - random_chunk.pos);
-                btrfs::logical_ino(fd, random_offset, false, |res| match res {
-                    Ok(inodes) => {
-                        for inode in inodes {
-                            btrfs::ino_lookup(fd, inode.root, inode.inum, |res| match res {
-                                Ok(path) => {
-                                    let root_path = roots.get_root(inode.root);
-                                    let root_path_it = root_path.iter().map(|s| s.as_str());
-                                    let inode_path = path.to_str().unwrap().split('/').filter(|s| !s.is_empty());
-                                    sample_tree.add(root_path_it.chain(inode_path));
-                                },
-                                Err(_) => {
-                                    // sample_tree.add(["DATA", "ERROR", "INO_LOOKUP"].into_iter());
-                                    // sample_tree.add(["ERROR", "INO_LOOKUP"].into_iter());
-                                },
-                            })
-                        }
-                    },
-                    Err(_) => {
-                        // sample_tree.add(["ERROR", "LOGICAL_TO_INO"].into_iter());
-                    },
-                });
+            res.push(',');
+        }
+        res.push(c);
+    }
+    res
+}
 
 
-            },
-            btrfs::BTRFS_BLOCK_GROUP_METADATA => {
-                // sample_tree.add(["METADATA"].into_iter());
 
-            },
-            btrfs::BTRFS_BLOCK_GROUP_SYSTEM => {
-                // sample_tree.add(["SYSTEM"].into_iter());
+// Name and parent of a subvolume, btrfs::find_root_backref except in tests
+type FindBackref = fn(i32, u64) -> Result<Option<(String, u64)>>;
 
-            },
-            _ => {
+struct Roots {
+    fd: i32,
+    m: HashMap<u64, Rc<Vec<String>>>,
+    find_backref: FindBackref,
+}
 
+impl Roots {
+    fn new(fd: i32) -> Self {
+        Self {
+            fd,
+            m: HashMap::from([(5, Rc::new(Vec::new()))]),
+            find_backref: btrfs::find_root_backref,
+        }
+    }
+    // Subvolumes whose backrefs are missing (e.g. deleted while sampling) or form a cycle
+    // get a synthetic UNRESOLVED_ROOT_<id> segment instead of their path.
+    fn get_root(&mut self, root_id: u64) -> Result<Rc<Vec<String>>> {
+        // walk up to the closest known root, collecting the names on the way
+        let mut chain: Vec<(u64, String)> = Vec::new();
+        let mut visited = HashSet::new();
+        let mut cur = root_id;
+        let base = loop {
+            if let Some(path) = self.m.get(&cur) {
+                break Rc::clone(path);
+            }
+            if !visited.insert(cur) {
+                // the roots from `cur` onwards form a cycle, `cur` stands for all of them
+                let pos = chain.iter().position(|(id, _)| *id == cur).unwrap();
+                chain.truncate(pos);
+                break Rc::new(vec![format!("UNRESOLVED_ROOT_{}", cur)]);
+            }
+            match (self.find_backref)(self.fd, cur)? {
+                Some((name, parent_id)) => {
+                    chain.push((cur, name));
+                    cur = parent_id;
+                },
+                None => {
+                    let path = Rc::new(vec![format!("UNRESOLVED_ROOT_{}", cur)]);
+                    self.m.insert(cur, Rc::clone(&path));
+                    break path;
+                },
             }
         };
+
+        let mut path_rc = base;
+        for (id, name) in chain.into_iter().rev() {
+            let mut path = Vec::clone(&path_rc);
+            path.push(name);
+            path_rc = Rc::new(path);
+            self.m.insert(id, Rc::clone(&path_rc));
+        }
+        Ok(path_rc)
     }
-    let total_time = start.elapsed();
 
-    let mut buf = String::new();
-    sample_tree.print(&mut buf, total_samples, total_chunk_length, Some(args.min_pct / 100.0))?;
-    std::io::stdout_locked().write_all(buf.as_bytes())?;
+    // Subvolume given by id or by its path below the top level subvolume, e.g. "home" or "@/snapshots/1"
+    fn find(&mut self, subvol: &str) -> Result<u64> {
+        if let Ok(root_id) = subvol.parse::<u64>() {
+            return Ok(root_id);
+        }
+        let path: Vec<&str> = subvol.split('/').filter(|s| !s.is_empty()).collect();
+        if path.is_empty() {
+            return Ok(btrfs::BTRFS_FS_TREE_OBJECTID as u64);
+        }
+        for root_id in btrfs::list_subvols(self.fd)? {
+            if *self.get_root(root_id)? == path {
+                return Ok(root_id);
+            }
+        }
+        Err(anyhow::anyhow!("subvolume {} not found", subvol))
+    }
+}
 
-    let resolution = total_chunk_length / total_samples as u64;
-    println!("elapsed={:?} per_sample={:?} resolution={}", total_time, total_time/(total_samples as u32), bytesize::to_string(resolution, true));
+// Inode item of an inode in a subvolume, btrfs::find_inode_item except in tests
+type FindInodeItem = fn(i32, u64, u64) -> Result<Option<btrfs::btrfs_inode_item>>;
 
-    
-    Ok(())
+struct Owners {
+    fd: i32,
+    inodes: HashMap<(u64, u64), Rc<String>>,
+    names: HashMap<(u32, u32), Rc<String>>,
+    find_inode_item: FindInodeItem,
+}
+
+impl Owners {
+    fn new(fd: i32) -> Self {
+        Self {
+            fd,
+            inodes: HashMap::new(),
+            names: HashMap::new(),
+            find_inode_item: btrfs::find_inode_item,
+        }
+    }
+
+    fn get_owner(&mut self, root_id: u64, inum: u64) -> Rc<String> {
+        if let Some(owner) = self.inodes.get(&(root_id, inum)) {
+            return Rc::clone(owner);
+        }
+        let owner = match (self.find_inode_item)(self.fd, root_id, inum) {
+            Ok(Some(inode_item)) => {
+                let (uid, gid) = (inode_item.uid, inode_item.gid);
+                let names = &mut self.names;
+                Rc::clone(names.entry((uid, gid)).or_insert_with(|| Rc::new(owner_name(uid, gid))))
+            },
+            _ => Rc::new("UNKNOWN_OWNER".to_owned()),
+        };
+        self.inodes.insert((root_id, inum), Rc::clone(&owner));
+        owner
+    }
+}
+
+// ids without a passwd/group entry (e.g. deleted users) are shown numerically
+fn owner_name(uid: u32, gid: u32) -> String {
+    let user = match nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    };
+    let group = match nix::unistd::Group::from_gid(nix::unistd::Gid::from_raw(gid)) {
+        Ok(Some(group)) => group.name,
+        _ => gid.to_string(),
+    };
+    format!("{}:{}", user, group)
+}
+
+// Parent directory of an inode in a subvolume, btrfs::find_inode_parent except in tests
+type FindInodeParent = fn(i32, u64, u64) -> Result<Option<u64>>;
+
+struct NoCowDirs {
+    fd: i32,
+    nocow: HashMap<(u64, u64), bool>,
+    parents: HashMap<(u64, u64), Option<u64>>,
+    find_inode_item: FindInodeItem,
+    find_inode_parent: FindInodeParent,
 }
 
+impl NoCowDirs {
+    fn new(fd: i32) -> Self {
+        Self {
+            fd,
+            nocow: HashMap::new(),
+            parents: HashMap::new(),
+            find_inode_item: btrfs::find_inode_item,
+            find_inode_parent: btrfs::find_inode_parent,
+        }
+    }
+
+    fn is_nocow(&mut self, root_id: u64, inum: u64) -> bool {
+        let (fd, find_inode_item) = (self.fd, self.find_inode_item);
+        *self.nocow.entry((root_id, inum)).or_insert_with(|| {
+            match find_inode_item(fd, root_id, inum) {
+                Ok(Some(inode_item)) => inode_item.flags & btrfs::BTRFS_INODE_NODATACOW != 0,
+                _ => false,
+            }
+        })
+    }
 
+    fn parent(&mut self, root_id: u64, inum: u64) -> Option<u64> {
+        let (fd, find_inode_parent) = (self.fd, self.find_inode_parent);
+        *self.parents.entry((root_id, inum)).or_insert_with(|| {
+            find_inode_parent(fd, root_id, inum).ok().flatten()
+        })
+    }
+
+    // Appends " [nocow]" to every directory in `path` whose inode has the NODATACOW flag,
+    // which new files inherit. `path` is the subvolume path (`root_len` segments) followed
+    // by the inode path, the subvolume root directory maps to the subvolume's own segment.
+    fn tag(&mut self, root_id: u64, inum: u64, root_len: usize, path: &mut [Cow<str>]) {
+        let mut dirs = Vec::new();
+        let mut cur = inum;
+        // the subvolume root directory refers to itself
+        while let Some(parent) = self.parent(root_id, cur) {
+            if parent == cur {
+                break;
+            }
+            dirs.push(parent);
+            cur = parent;
+        }
+        dirs.reverse();
+        // hardlinks can make the walked refs disagree with the resolved path
+        if root_len + dirs.len() != path.len() {
+            return;
+        }
+        for (i, dir) in dirs.into_iter().enumerate() {
+            if (root_len + i) == 0 || !self.is_nocow(root_id, dir) {
+                continue;
+            }
+            let segment = &mut path[root_len + i - 1];
+            *segment = Cow::Owned(format!("{} [nocow]", segment));
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Number of samples to take before printing the tree (the interactive browser samples until it is closed)
+    #[clap(short, long, alias = "max-samples", default_value_t = 100000)]
+    samples: u64,
+
+    /// Take as many samples as needed for each one to stand for this much space (e.g. 512K, 4M, 1G) instead of --samples
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size), conflicts_with = "samples")]
+    resolution: Option<u64>,
+
+    /// Take at most this many samples per second, to keep the load on a busy filesystem down.
+    /// The estimate converges just as slowly, a run needs samples/rate seconds for the same resolution
+    #[clap(long, value_name = "SAMPLES_PER_SEC", parse(try_from_str = parse_rate))]
+    rate: Option<f64>,
+
+    /// Seed for picking sample positions, runs over an unchanged filesystem then produce the same tree
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Stop sampling after this long (e.g. 30s, 5m, 1h30m), also in the interactive browser
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    duration: Option<Duration>,
+
+    /// Filter tree by min disk usage percentage 0..100
+    #[clap(short, long, default_value_t = 1.0)]
+    min_pct: f64,
+
+    /// Group usage by file owner (user:group) before the path
+    #[clap(long)]
+    by_owner: bool,
+
+    /// Group data by file extension (.mp4, .log, <none>) instead of by path
+    #[clap(long)]
+    by_extension: bool,
+
+    /// Estimate how much data could be reclaimed by deduplication (scans the whole csum tree)
+    #[clap(long)]
+    dedup_estimate: bool,
+
+    /// Print the N largest paths as a flat list instead of the tree. Every level is listed, so a
+    /// directory comes before its largest children
+    #[clap(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Only list files and other nodes without children with --top
+    #[clap(long, requires = "top")]
+    top_leaves: bool,
+
+    /// Print the N most sampled inodes with their paths
+    #[clap(long, value_name = "N")]
+    top_inodes: Option<usize>,
+
+    /// Merge chains of single-child directories into one row
+    #[clap(long)]
+    collapse_single_child: bool,
+
+    /// Dump every item of a tree (ROOT, EXTENT, CHUNK, DEV, FS, CSUM, QUOTA, UUID, FREE_SPACE or an id) instead of sampling
+    #[clap(long, value_name = "TREE", parse(try_from_str = btrfs::parse_tree_id))]
+    raw_search: Option<u64>,
+
+    /// Only dump the items of this objectid with --raw-search (uses a fixed-id search key)
+    #[clap(long, value_name = "OBJECTID", requires = "raw-search")]
+    raw_objectid: Option<u64>,
+
+    /// Only dump the items of this key type (e.g. 168 for EXTENT_ITEM) with --raw-search
+    #[clap(long, value_name = "TYPE", requires = "raw-search")]
+    raw_type: Option<u8>,
+
+    /// Compute percentages against successfully resolved samples only and report the
+    /// ERROR share as coverage. By default percentages are against all samples taken.
+    #[clap(long)]
+    exclude_errors_from_total: bool,
+
+    /// Also show samples that couldn't be resolved as ERROR/<kind> in the tree. They are
+    /// always counted in the error summary
+    #[clap(long)]
+    show_errors_in_tree: bool,
+
+    /// Print only the top N levels of the tree, deeper usage is included in the totals of the last level shown
+    #[clap(long, value_name = "N")]
+    depth: Option<usize>,
+
+    /// Always show this path (e.g. /DATA/home/user) and its ancestors, even below --min-pct. Can be repeated
+    #[clap(long, value_name = "PATH")]
+    pin: Vec<String>,
+
+    /// Count files under paths matching this glob (e.g. /var/lib/docker or /snapshots/*) as DATA/EXCLUDED. Can be repeated
+    #[clap(long, value_name = "PATTERN", parse(try_from_str = glob::Pattern::new))]
+    exclude: Vec<glob::Pattern>,
+
+    /// Resolve a sample to every file referencing its extent, not just the ones whose file extent
+    /// covers the sampled byte. Resolves more samples of partially overwritten extents, but a
+    /// reflinked file then also gets samples of parts of the extent it doesn't use
+    #[clap(long)]
+    ignore_offset: bool,
+
+    /// Count every sample once even when its extent is shared by reflinks or snapshots, instead of
+    /// once per reference. Shared data is then split evenly among the files referencing it
+    #[clap(long)]
+    unique: bool,
+
+    /// Spread the usage of files with hardlinks evenly across their names instead of counting it
+    /// under the first one. Costs one more lookup per sample, and two for files with hardlinks
+    #[clap(long)]
+    hardlinks: bool,
+
+    /// Mark directories with the NODATACOW (chattr +C) attribute as "[nocow]"
+    #[clap(long)]
+    nocow: bool,
+
+    /// Print the referenced and exclusive size of one subvolume (by root id) instead of sampling
+    #[clap(long, value_name = "ROOT_ID")]
+    subvol_size: Option<u64>,
+
+    /// Print the chunk map (logical offset, length, type and profile of every chunk) and the space allocated per type instead of sampling
+    #[clap(long)]
+    chunks: bool,
+
+    /// Print the exact size of all data checksums (scans the whole csum tree) instead of sampling
+    #[clap(long)]
+    csum_usage: bool,
+
+    /// Print "logical -> /path" lines for every resolved sample (like btrfs inspect-internal logical-resolve) instead of the tree
+    #[clap(long)]
+    inspect_dump: bool,
+
+    /// Only report files below this directory (e.g. home/user or @/backups, relative to the top level
+    /// subvolume), sizes are estimated the same way while percentages are of the directory.
+    /// Files elsewhere are counted as DATA/OUTSIDE while sampling. The report is printed, the
+    /// interactive browser is not started
+    #[clap(long, value_name = "DIR", conflicts_with_all = &["by-owner", "by-extension", "compression", "nocow"])]
+    start_dir: Option<String>,
+
+    /// Only resolve files of the default subvolume, others are counted as DATA/OTHER_SUBVOL
+    #[clap(long)]
+    default_subvol: bool,
+
+    /// Only resolve files of this subvolume (id or path like home or @/snapshots/1), others are counted as DATA/OTHER_SUBVOL
+    #[clap(long, value_name = "SUBVOL", conflicts_with = "default-subvol")]
+    subvol: Option<String>,
+
+    /// Keep at most N nodes in the tree by folding the smallest leaves into "<others>"
+    #[clap(long, value_name = "N")]
+    max_nodes: Option<usize>,
+
+    /// Fold paths below a tenth of --min-pct into "<others>" every 10000 samples, which bounds
+    /// memory on long runs over large filesystems
+    #[clap(long)]
+    prune: bool,
+
+    /// Split the files of each directory into [recent] and [old] by whether their data was written
+    /// within the last N transactions (one is committed at least every 30s by default)
+    #[clap(long, value_name = "N")]
+    age_split: Option<u64>,
+
+    /// Print the tree as a single line of JSON ({name, total, disk_bytes, fraction, children}) instead of text.
+    /// The root also has a summary ({data, metadata, system, errors, unreachable, estimated} in bytes, with
+    /// used and the ratio of estimated to it when btrfs reports its used space)
+    #[clap(long)]
+    json: bool,
+
+    /// File samples of space the free space tree has as free under FREE (split by chunk type) instead of
+    /// looking them up, so that totals add up to the allocated space. Needs space_cache=v2
+    #[clap(long)]
+    free_space: bool,
+
+    /// Name the top-level nodes after the chunks' profile too, e.g. DATA[raid1] or METADATA[dup],
+    /// and explain the profiles below the tree. Sizes are physical unless --logical, so a DUP or RAID1 node takes twice its data
+    #[clap(long, conflicts_with = "start-dir")]
+    profiles: bool,
+
+    /// Split METADATA by the tree (ROOT, EXTENT, CSUM, FS/<subvolume> etc.) its blocks belong to.
+    /// Costs one more lookup per metadata sample
+    #[clap(long)]
+    metadata_detail: bool,
+
+    /// Group data by the compression of its extent ([none], [zlib], [lzo] or [zstd]) right below DATA.
+    /// Costs one more lookup per sample
+    #[clap(long)]
+    compression: bool,
+
+    /// Count space preallocated by fallocate but not written yet as "<file> [prealloc]".
+    /// Costs one more lookup per sample
+    #[clap(long)]
+    prealloc: bool,
+
+    /// Only sample data chunks. Sizes stay the same while percentages are of the data chunks
+    #[clap(long, conflicts_with = "metadata-only")]
+    data_only: bool,
+
+    /// Only sample metadata chunks, e.g. with --metadata-detail. Percentages are of the metadata chunks
+    #[clap(long)]
+    metadata_only: bool,
+
+    /// Size chunks by their logical length. By default DUP, RAID1 and parity copies count towards usage
+    #[clap(long)]
+    logical: bool,
+
+    /// Start from the samples saved by --save, e.g. to resume a long run
+    #[clap(long, value_name = "FILE")]
+    load: Option<String>,
+
+    /// Combine profiles saved by --save (e.g. of several machines) into one report instead of sampling.
+    /// Their sizes add up, samples are rescaled so that estimated sizes stay comparable. Takes no path
+    #[clap(long, value_name = "FILE", min_values = 1)]
+    merge: Vec<String>,
+
+    /// Save all samples (including --load ones) as JSON when sampling ends
+    #[clap(long, value_name = "FILE")]
+    save: Option<String>,
+
+    /// Print one "path,samples,fraction,disk_bytes" CSV row per node above --min-pct instead of the tree
+    #[clap(long)]
+    csv: bool,
+
+    /// Print one "DATA;dir;file count" line per path in the collapsed stack format of flamegraph.pl/inferno instead of the tree
+    #[clap(long)]
+    folded: bool,
+
+    /// Serve the usage of every path above --min-pct, the number of samples and errors as Prometheus metrics on this address (e.g. 127.0.0.1:9000) while sampling
+    #[cfg(feature = "metrics")]
+    #[clap(long, value_name = "ADDR")]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Print how much the usage of every path changed compared to a profile saved with --save, largest growth first
+    #[clap(long, value_name = "FILE")]
+    baseline: Option<String>,
+
+    /// Print only the paths whose usage changed by at least --min-pct since the profile saved in FILE by
+    /// the previous run, then save this run's profile there. The first run, without FILE, prints every path.
+    /// Changes are printed like --baseline, or as JSON or CSV with --json or --csv
+    #[clap(long, value_name = "FILE", conflicts_with = "baseline")]
+    incremental_output: Option<String>,
+
+    /// Print the tree once instead of browsing it interactively. Implied when stdout is not a terminal.
+    /// In the browser u switches the value column between size (physical unless --logical, there is
+    /// no per-path split between the two) and samples. It lists every child regardless of --min-pct
+    #[clap(long)]
+    no_tui: bool,
+
+    /// Redraw the printed tree every second while sampling, like top. Only when stdout is a terminal.
+    /// Shown paths stay until they drop below 0.8 × --min-pct, hidden ones appear at 1.2 × --min-pct
+    #[clap(long)]
+    follow: bool,
+
+    /// Don't report progress on stderr while sampling, and only log errors
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Log more on stderr: -v for a progress summary every second, -vv for every sample. RUST_LOG
+    /// (e.g. RUST_LOG=btdu_rs=trace) takes precedence. Implies --no-tui
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: usize,
+
+    /// Write the report (also --json, --csv etc.) to this file instead of stdout, progress still goes to stderr
+    #[clap(short, long, value_name = "FILE")]
+    output: Option<String>,
+
+    /// Color the printed tree: auto (the default) colors it when writing to a terminal and NO_COLOR is not set
+    #[clap(long, arg_enum, value_name = "WHEN")]
+    color: Option<ColorChoice>,
+
+    /// Mounted btrfs path
+    #[clap(required_unless_present = "merge")]
+    path: Option<String>,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl Args {
+    // options that only affect the printed report fall back to it
+    fn interactive(&self) -> bool {
+        !(self.no_tui || self.follow || self.output.is_some() || self.top.is_some() || self.baseline.is_some() || self.incremental_output.is_some() || self.json || self.csv || self.folded || self.inspect_dump || self.top_inodes.is_some() || self.dedup_estimate || self.exclude_errors_from_total || self.start_dir.is_some() || self.depth.is_some() || self.collapse_single_child || !self.pin.is_empty() || self.color.is_some() || self.verbose > 0)
+            && terminal_width().is_some()
+    }
+}
+
+// A node of the --json output
+#[derive(Serialize)]
+struct JsonNode<'a> {
+    name: &'a str,
+    total: usize,
+    disk_bytes: u64,
+    fraction: f64,
+    children: Vec<JsonNode<'a>>,
+    // only on the root, next to its fields
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'a Summary>,
+}
+
+// Split of the sampled space by chunk type, in estimated bytes. The same as the first level of
+// the tree, except that errors are counted whether or not they are shown in it.
+#[derive(Debug, Serialize)]
+struct Summary {
+    data: u64,
+    metadata: u64,
+    system: u64,
+    free: u64,
+    errors: u64,
+    // data no file refers to (DATA/UNREACHABLE), part of `data`
+    unreachable: u64,
+    // bytes btrfs reports as used, when it could be asked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    used: Option<u64>,
+    // resolved samples minus free space, what `used` should come close to
+    estimated: u64,
+    // estimated / used, far from 1 means too few samples or too many errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ratio: Option<f64>,
+}
+
+impl Summary {
+    fn new(profile: &Profile, total_length: u64, used: Option<u64>) -> Self {
+        let bytes_per_sample = total_length as f64 / profile.total_samples.max(1) as f64;
+        // with --profiles a category is split over one node per profile
+        let bytes = |category: SampleCategory, path: &[&str]| profile.tree.children.iter()
+            .filter(|(k, _)| SampleCategory::from_label(k) == Some(category))
+            .filter_map(|(_, node)| node.get(path.iter().copied()))
+            .map(|node| node.total_bytes(bytes_per_sample))
+            .sum();
+        let free = bytes(SampleCategory::Free, &[]);
+        // unresolved samples are mostly free space in data chunks, while free space in metadata chunks counts as used
+        // unless --free-space tells it apart
+        let resolved = profile.total_samples.saturating_sub(profile.errors.total());
+        let estimated = ((resolved as f64 * bytes_per_sample) as u64).saturating_sub(free);
+        Self {
+            data: bytes(SampleCategory::Data, &[]),
+            metadata: bytes(SampleCategory::Metadata, &[]),
+            system: bytes(SampleCategory::System, &[]),
+            free,
+            errors: (profile.errors.total() as f64 * bytes_per_sample) as u64,
+            unreachable: bytes(SampleCategory::Data, &["UNREACHABLE"]),
+            used,
+            estimated,
+            ratio: used.filter(|&used| used > 0).map(|used| estimated as f64 / used as f64),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "data={} metadata={} system={} free={} errors={} unreachable={}",
+            bytesize::to_string(self.data, true), bytesize::to_string(self.metadata, true), bytesize::to_string(self.system, true),
+            bytesize::to_string(self.free, true), bytesize::to_string(self.errors, true), bytesize::to_string(self.unreachable, true))
+    }
+}
+
+// Samples that couldn't be resolved by kind (e.g. INO_LOOKUP), counted apart from the usage tree
+#[derive(Default, Serialize, Deserialize)]
+struct SampleErrors {
+    kinds: BTreeMap<String, usize>,
+}
+
+impl SampleErrors {
+    fn total(&self) -> usize {
+        self.kinds.values().sum()
+    }
+}
+
+// Counts an error, and files it under ERROR/<kind> with --show-errors-in-tree
+fn add_error(sample_tree: &mut SampleTree, errors: &mut SampleErrors, in_tree: bool, kind: &str) {
+    trace!(kind, "sample not resolved");
+    match errors.kinds.get_mut(kind) {
+        Some(count) => *count += 1,
+        None => {
+            errors.kinds.insert(kind.to_owned(), 1);
+        },
+    }
+    if in_tree {
+        sample_tree.add([SampleCategory::Error.as_str(), kind].into_iter());
+    }
+}
+
+// Everything sampled so far, shared with the interactive browser and stored by --save
+#[derive(Default, Serialize, Deserialize)]
+struct Profile {
+    tree: SampleTree,
+    #[serde(default)]
+    errors: SampleErrors,
+    total_samples: usize,
+    // bytes the samples were spread over
+    #[serde(default)]
+    total_length: u64,
+}
+
+impl Profile {
+    fn load(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("opening {}", path))?;
+        serde_json::from_reader(std::io::BufReader::new(file)).with_context(|| format!("loading {}", path))
+    }
+
+    // Prunes what is below a tenth of min_pct, far enough below it that pruned paths are
+    // unlikely to have crossed it by the end of the run. The categories themselves always stay.
+    fn prune(&mut self, min_pct: f64, pins: &[Vec<String>]) {
+        let min_total = (self.total_samples as f64 * min_pct / 1000.0) as usize;
+        for (k, v) in &mut self.tree.children {
+            let pins: Vec<&[String]> = pins.iter().filter(|p| p.first() == Some(k)).map(|p| &p[1..]).collect();
+            v.prune(min_total, &pins);
+        }
+    }
+
+    // Adds a profile saved by --save, e.g. of another filesystem, whose size adds to this one.
+    // Samples of both are rescaled to the smaller bytes per sample, so sizes stay what they were.
+    fn merge_from_reader(&mut self, r: impl std::io::Read) -> Result<()> {
+        let other: Profile = serde_json::from_reader(r)?;
+        if other.total_samples == 0 {
+            return Ok(());
+        }
+        if other.total_length == 0 {
+            anyhow::bail!("the profile has no total length, it was saved by an older version");
+        }
+        if self.total_samples == 0 {
+            *self = other;
+            return Ok(());
+        }
+        let bytes_per_sample = self.total_length as f64 / self.total_samples as f64;
+        let other_bytes_per_sample = other.total_length as f64 / other.total_samples as f64;
+        let target = bytes_per_sample.min(other_bytes_per_sample);
+        let mut merged = Profile {
+            total_length: self.total_length + other.total_length,
+            ..Profile::default()
+        };
+        for (profile, scale) in [(std::mem::take(self), bytes_per_sample / target), (other, other_bytes_per_sample / target)] {
+            // samples outside the tree (errors not counted in it) are scaled on their own
+            let outside = profile.total_samples.saturating_sub(profile.tree.total);
+            merged.total_samples += merged.tree.merge(profile.tree, scale) + (outside as f64 * scale).round() as usize;
+            for (kind, n) in profile.errors.kinds {
+                *merged.errors.kinds.entry(kind).or_default() += (n as f64 * scale).round() as usize;
+            }
+        }
+        *self = merged;
+        Ok(())
+    }
+
+    fn save(&self, path: &str) -> Result<()> {
+        let file = std::fs::File::create(path).with_context(|| format!("creating {}", path))?;
+        let mut w = std::io::BufWriter::new(file);
+        serde_json::to_writer(&mut w, self)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ChunkInfo {
+    pos: u64,
+    chunk_offset: u64,
+    chunk_length: u64,
+    // space the chunk takes up in the sampled range, its physical size unless --logical
+    sample_length: u64,
+    chunk_type: u64,
+}
+
+impl ChunkInfo {
+    // Logical address at `pos` in the sampled range, every copy of a logical byte maps back to it
+    fn logical_offset(&self, pos: u64) -> u64 {
+        self.chunk_offset + ((pos - self.pos) as u128 * self.chunk_length as u128 / self.sample_length as u128) as u64
+    }
+}
+
+// Index of the chunk `pos` falls into, by binary search as chunks are laid out back to back
+// in increasing pos order
+fn chunk_at(chunks: &[ChunkInfo], pos: u64) -> usize {
+    chunks.partition_point(|c| c.pos + c.sample_length <= pos)
+}
+
+// Chunks in the order of their logical offset, laid out one after another in the sampled range
+fn read_chunks(fd: i32, args: &Args) -> Result<Vec<ChunkInfo>> {
+    let mut chunks = Vec::new();
+    let mut total_chunk_length = 0;
+    for item in btrfs::tree_search(fd, btrfs::BTRFS_CHUNK_TREE_OBJECTID as u64, btrfs::SearchKey::ALL) {
+        let (sh, data) = item?;
+        match sh.type_ {
+            btrfs::BTRFS_CHUNK_ITEM_KEY => {
+                let chunk: btrfs::btrfs_chunk = match btrfs::read_item(&sh, &data) {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        warn!("skipping chunk: {}", err);
+                        continue;
+                    },
+                };
+                // chunks of other types are left out of the sampled range altogether
+                let chunk_type = chunk.type_ as u32;
+                if (args.data_only && chunk_type & btrfs::BTRFS_BLOCK_GROUP_DATA == 0)
+                    || (args.metadata_only && chunk_type & btrfs::BTRFS_BLOCK_GROUP_METADATA == 0) {
+                    continue;
+                }
+                let sample_length = if args.logical {
+                    chunk.length
+                } else {
+                    btrfs::chunk_physical_length(&chunk)
+                };
+                chunks.push(ChunkInfo{
+                    pos: total_chunk_length,
+                    chunk_offset:sh.offset, 
+                    chunk_length:chunk.length,
+                    sample_length,
+                    chunk_type: chunk.type_,
+                });
+                total_chunk_length += sample_length;
+            },
+            _ => {}
+        };
+    }
+    Ok(chunks)
+}
+
+fn print_chunks(out: &mut impl Write, chunks: &[ChunkInfo]) -> Result<()> {
+    let mut chunks: Vec<_> = chunks.iter().collect();
+    chunks.sort_by_key(|c| c.chunk_offset);
+    writeln!(out, "{:>20} {:>12}  {:<13} PROFILE", "OFFSET", "LENGTH", "TYPE")?;
+    let mut allocated: BTreeMap<(&str, &str), (usize, u64)> = BTreeMap::new();
+    for c in chunks {
+        let kind = btrfs::block_group_type_name(c.chunk_type);
+        let profile = btrfs::profile_name(c.chunk_type);
+        writeln!(out, "{:>20} {:>12}  {:<13} {}", c.chunk_offset, bytesize::to_string(c.chunk_length, true), kind, profile)?;
+        let total = allocated.entry((kind, profile)).or_default();
+        total.0 += 1;
+        total.1 += c.chunk_length;
+    }
+    writeln!(out)?;
+    for ((kind, profile), (count, length)) in allocated {
+        writeln!(out, "{}, {}: chunks={} allocated={}", kind, profile, count, bytesize::to_string(length, true))?;
+    }
+    Ok(())
+}
+
+// Spaces samples evenly at --rate, a sample that ran late doesn't let the next ones catch up
+struct RateLimit {
+    interval: Duration,
+    next: Instant,
+}
+
+impl RateLimit {
+    fn new(rate: f64) -> Self {
+        Self { interval: Duration::from_secs_f64(1.0 / rate), next: Instant::now() }
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if let Some(delay) = self.next.checked_duration_since(now) {
+            std::thread::sleep(delay);
+        }
+        self.next = self.next.max(now) + self.interval;
+    }
+}
+
+// Paths of files looked up before, since files are usually sampled many times.
+// It is emptied when full, which bounds memory on filesystems with many files.
+struct PathCache {
+    paths: HashMap<(u64, u64), Rc<str>>,
+    capacity: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl PathCache {
+    fn new(capacity: usize) -> Self {
+        Self { paths: HashMap::new(), capacity, hits: 0, misses: 0 }
+    }
+
+    // Path of the inode (root, inum), from `lookup` unless it is cached. Failed lookups are not cached.
+    fn get_or_lookup(&mut self, key: (u64, u64), lookup: impl FnOnce() -> Result<String>) -> Result<Rc<str>> {
+        if let Some(path) = self.paths.get(&key) {
+            self.hits += 1;
+            return Ok(Rc::clone(path));
+        }
+        self.misses += 1;
+        let path: Rc<str> = Rc::from(lookup()?);
+        if self.paths.len() >= self.capacity {
+            self.paths.clear();
+        }
+        self.paths.insert(key, Rc::clone(&path));
+        Ok(path)
+    }
+
+    fn hit_rate(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses).max(1) as f64
+    }
+}
+
+// Picks random positions in the chunk map and files what is stored there into a SampleTree
+struct Sampler<'a> {
+    fd: i32,
+    args: &'a Args,
+    chunks: Vec<ChunkInfo>,
+    // sum of the chunks' sample_length
+    total_chunk_length: u64,
+    uniform: Uniform<u64>,
+    rng: StdRng,
+    roots: Roots,
+    owners: Owners,
+    nocow_dirs: NoCowDirs,
+    generation: u64,
+    // bytes in use according to the space info, sized like sample_length
+    used_length: u64,
+    // size of tree blocks, for --metadata-detail
+    nodesize: u32,
+    sectorsize: u32,
+    // free ranges of the chunks sampled so far by chunk_offset, for --free-space
+    free_space: HashMap<u64, Vec<Range<u64>>>,
+    default_subvol: u64,
+    // samples of other subvolumes are counted as DATA/OTHER_SUBVOL
+    only_subvol: Option<u64>,
+    // --start-dir split into segments, files outside of it are counted as DATA/OUTSIDE
+    start_dir: Vec<String>,
+    inode_stats: HashMap<(u64, u64), u64>,
+    // inodes whose path couldn't be looked up
+    unresolved_inodes: HashSet<(u64, u64)>,
+    path_cache: PathCache,
+    inspect_lines: String,
+}
+
+impl<'a> Sampler<'a> {
+    fn new(fd: i32, args: &'a Args) -> Result<Self> {
+        let chunks = read_chunks(fd, args)?;
+        let total_chunk_length = chunks.last().map_or(0, |c| c.pos + c.sample_length);
+
+        // space info reports logical sizes, chunks of the same type and profile tell how much space they take up
+        let mut used_length = 0;
+        for space in btrfs::space_info(fd)? {
+            if space.flags & btrfs::BTRFS_SPACE_INFO_GLOBAL_RSV != 0 {
+                continue;
+            }
+            let (logical, sampled) = chunks.iter()
+                .filter(|c| c.chunk_type == space.flags)
+                .fold((0, 0), |(logical, sampled), c| (logical + c.chunk_length, sampled + c.sample_length));
+            if logical > 0 {
+                used_length += (space.used_bytes as u128 * sampled as u128 / logical as u128) as u64;
+            }
+        }
+
+        let fs_info = btrfs::fs_info(fd)?;
+        if args.free_space {
+            btrfs::tree_search(fd, btrfs::BTRFS_FREE_SPACE_TREE_OBJECTID as u64, btrfs::SearchKey::ALL).next().transpose()
+                .context("--free-space needs the free space tree (mount option space_cache=v2)")?;
+        }
+
+        let generation = if args.age_split.is_some() {
+            btrfs::current_generation(fd)?
+        } else {
+            0
+        };
+        let default_subvol = btrfs::find_default_subvol(fd)?.unwrap_or(btrfs::BTRFS_FS_TREE_OBJECTID as u64);
+        let mut roots = Roots::new(fd);
+        let only_subvol = if args.default_subvol {
+            Some(default_subvol)
+        } else if let Some(subvol) = &args.subvol {
+            Some(roots.find(subvol)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            fd,
+            args,
+            chunks,
+            total_chunk_length,
+            uniform: sample_range(total_chunk_length)?,
+            rng: match args.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            roots,
+            owners: Owners::new(fd),
+            nocow_dirs: NoCowDirs::new(fd),
+            generation,
+            used_length,
+            nodesize: fs_info.nodesize,
+            sectorsize: fs_info.sectorsize,
+            free_space: HashMap::new(),
+            default_subvol,
+            only_subvol,
+            start_dir: args.start_dir.iter().flat_map(|p| p.split('/')).filter(|s| !s.is_empty()).map(|s| s.to_owned()).collect(),
+            inode_stats: HashMap::new(),
+            unresolved_inodes: HashSet::new(),
+            path_cache: PathCache::new(PATH_CACHE_CAPACITY),
+            inspect_lines: String::new(),
+        })
+    }
+
+    // Whether the free space tree has `pos` of the chunk as free, loading the chunk's free ranges once
+    fn is_free(&mut self, chunk: usize, pos: u64) -> Result<bool> {
+        let chunk = &self.chunks[chunk];
+        let logical = chunk.logical_offset(pos);
+        let ranges = match self.free_space.entry(chunk.chunk_offset) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(btrfs::free_space(self.fd, chunk.chunk_offset, chunk.chunk_length, self.sectorsize)?),
+        };
+        let i = ranges.partition_point(|r| r.end <= logical);
+        Ok(ranges.get(i).is_some_and(|r| r.contains(&logical)))
+    }
+
+    // Takes one sample. Shared extents add one entry per referencing inode.
+    fn sample(&mut self, profile: &mut Profile) {
+        let random_pos = self.uniform.sample(&mut self.rng);
+        let chunk = chunk_at(&self.chunks, random_pos);
+        if self.args.free_space {
+            let chunk_type = self.chunks[chunk].chunk_type;
+            match self.is_free(chunk, random_pos) {
+                Ok(true) => {
+                    let label = SampleCategory::Free.label(chunk_type, self.args.profiles);
+                    profile.tree.add([label.as_ref(), btrfs::block_group_type_name(chunk_type)].into_iter());
+                    return;
+                },
+                Ok(false) => {},
+                Err(_) => {
+                    add_error(&mut profile.tree, &mut profile.errors, self.args.show_errors_in_tree, "FREE_SPACE_LOOKUP");
+                    return;
+                },
+            }
+        }
+
+        let (sample_tree, errors) = (&mut profile.tree, &mut profile.errors);
+        let (fd, args, generation, only_subvol, start_dir) = (self.fd, self.args, self.generation, self.only_subvol, &self.start_dir);
+        let errors_in_tree = args.show_errors_in_tree;
+        let (roots, owners, nocow_dirs) = (&mut self.roots, &mut self.owners, &mut self.nocow_dirs);
+        let (inode_stats, unresolved_inodes, inspect_lines) = (&mut self.inode_stats, &mut self.unresolved_inodes, &mut self.inspect_lines);
+        let path_cache = &mut self.path_cache;
+        let rng = &mut self.rng;
+        let random_chunk = &self.chunks[chunk];
+        trace!(logical = random_chunk.logical_offset(random_pos), chunk_type = btrfs::block_group_type_name(random_chunk.chunk_type), "sample");
+
+        match (random_chunk.chunk_type as u32) & btrfs::BTRFS_BLOCK_GROUP_TYPE_MASK {
+            btrfs::BTRFS_BLOCK_GROUP_DATA => {
+                let label = SampleCategory::Data.label(random_chunk.chunk_type, args.profiles);
+                let category = label.as_ref();
+                let random_offset = random_chunk.logical_offset(random_pos);
+                btrfs::logical_ino(fd, random_offset, args.ignore_offset, |res| match res {
+                    Ok(inodes) => {
+                        // an allocated extent that no file refers to, e.g. one of a deleted subvolume not cleaned up yet
+                        if inodes.is_empty() {
+                            sample_tree.add([category, "UNREACHABLE"].into_iter());
+                        }
+                        // one reference stands for the whole sample, picked at random so that over many samples
+                        // shared data is split evenly among its references and totals add up to the space used
+                        let inodes = if args.unique && !inodes.is_empty() {
+                            let i = rng.gen_range(0..inodes.len());
+                            &inodes[i..=i]
+                        } else {
+                            inodes
+                        };
+                        for inode in inodes {
+                            *inode_stats.entry((inode.root, inode.inum)).or_insert(0) += 1;
+                            if only_subvol.is_some_and(|root| inode.root != root) {
+                                sample_tree.add([category, "OTHER_SUBVOL"].into_iter());
+                                continue;
+                            }
+                            let res = path_cache.get_or_lookup((inode.root, inode.inum), || btrfs::ino_lookup_sync(fd, inode.root, inode.inum));
+                            match res {
+                                Ok(path) => {
+                                    let root_path = match roots.get_root(inode.root) {
+                                        Ok(root_path) => root_path,
+                                        Err(err) => {
+                                            add_error(sample_tree, errors, errors_in_tree, lookup_error_kind(&err, "ROOT_LOOKUP"));
+                                            continue;
+                                        },
+                                    };
+                                    let link = if args.hardlinks {
+                                        random_link(fd, inode.root, inode.inum, rng)
+                                    } else {
+                                        None
+                                    };
+                                    let inode_path = link.as_deref().unwrap_or(&path).split('/').filter(|s| !s.is_empty());
+                                    let mut full_path: Vec<Cow<str>> = root_path.iter().map(|s| Cow::Borrowed(s.as_str())).chain(inode_path.map(Cow::Borrowed)).collect();
+                                    if is_excluded(&args.exclude, &full_path) {
+                                        sample_tree.add([category, "EXCLUDED"].into_iter());
+                                        continue;
+                                    }
+                                    if full_path.len() < start_dir.len() || full_path.iter().zip(start_dir).any(|(a, b)| a != b) {
+                                        sample_tree.add([category, "OUTSIDE"].into_iter());
+                                        continue;
+                                    }
+                                    if args.inspect_dump {
+                                        // one line per path, shared extents resolve to several
+                                        inspect_lines.push_str(&inspect_line(random_offset, &full_path));
+                                    }
+                                    if args.by_extension {
+                                        let extension = file_extension(full_path.last().map_or("", |s| s.as_ref())).to_owned();
+                                        full_path = vec![Cow::Owned(extension)];
+                                    }
+                                    if args.nocow && !args.by_extension {
+                                        nocow_dirs.tag(inode.root, inode.inum, root_path.len(), &mut full_path);
+                                    }
+                                    // one extra lookup per sample, shared by everything that needs the file extent
+                                    let extent = if args.age_split.is_some() || args.compression || args.prealloc {
+                                        btrfs::find_file_extent(fd, inode.root, inode.inum, inode.offset).ok().flatten()
+                                    } else {
+                                        None
+                                    };
+                                    if args.prealloc {
+                                        if let (Some(extent), Some(file_name)) = (extent, full_path.last_mut()) {
+                                            if extent.type_ as u32 == btrfs::BTRFS_FILE_EXTENT_PREALLOC {
+                                                *file_name = Cow::Owned(format!("{} [prealloc]", file_name));
+                                            }
+                                        }
+                                    }
+                                    if let Some(max_age) = args.age_split {
+                                        let age = age_bucket(generation, extent.map(|extent| extent.generation), max_age);
+                                        // files of each directory are grouped by age
+                                        full_path.insert(full_path.len().saturating_sub(1), Cow::Borrowed(age));
+                                    }
+                                    let owner = if args.by_owner {
+                                        Some(owners.get_owner(inode.root, inode.inum))
+                                    } else {
+                                        None
+                                    };
+                                    let compression = if args.compression {
+                                        Some(match extent.map(|extent| extent.compression) {
+                                            Some(btrfs::BTRFS_COMPRESS_NONE) => "[none]",
+                                            Some(btrfs::BTRFS_COMPRESS_ZLIB) => "[zlib]",
+                                            Some(btrfs::BTRFS_COMPRESS_LZO) => "[lzo]",
+                                            Some(btrfs::BTRFS_COMPRESS_ZSTD) => "[zstd]",
+                                            _ => "[unknown compression]",
+                                        })
+                                    } else {
+                                        None
+                                    };
+                                    trace!(root = inode.root, inum = inode.inum, path = %full_path.join("/"), "resolved");
+                                    let owner_it = owner.iter().map(|s| s.as_str());
+                                    sample_tree.add(std::iter::once(category).chain(compression).chain(owner_it).chain(full_path.iter().map(|s| s.as_ref())));
+                                },
+                                Err(_) => {
+                                    unresolved_inodes.insert((inode.root, inode.inum));
+                                    add_error(sample_tree, errors, errors_in_tree, "INO_LOOKUP");
+                                },
+                            }
+                        }
+                    },
+                    Err(err) if err.is::<btrfs::TooManyRefs>() => {
+                        add_error(sample_tree, errors, errors_in_tree, "TOO_MANY_REFS");
+                    },
+                    Err(_) => {
+                        add_error(sample_tree, errors, errors_in_tree, "LOGICAL_TO_INO");
+                    },
+                });
+            },
+            btrfs::BTRFS_BLOCK_GROUP_METADATA if args.metadata_detail => {
+                let label = SampleCategory::Metadata.label(random_chunk.chunk_type, args.profiles);
+                let category = label.as_ref();
+                match btrfs::find_tree_block_owner(fd, random_chunk.logical_offset(random_pos), self.nodesize) {
+                    // blocks of the FS tree and subvolumes are filed under their subvolume's path
+                    Ok(Some(btrfs::TreeBlockOwner::Root(root))) if root == btrfs::BTRFS_FS_TREE_OBJECTID as u64 || (btrfs::BTRFS_FIRST_FREE_OBJECTID as u64..=btrfs::BTRFS_LAST_FREE_OBJECTID as u64).contains(&root) => {
+                        match roots.get_root(root) {
+                            Ok(root_path) => sample_tree.add([category, "FS"].into_iter().chain(root_path.iter().map(|s| s.as_str()))),
+                            Err(err) => add_error(sample_tree, errors, errors_in_tree, lookup_error_kind(&err, "ROOT_LOOKUP")),
+                        }
+                    },
+                    Ok(Some(btrfs::TreeBlockOwner::Root(root))) => {
+                        // e.g. relocation trees, which have negative ids
+                        let name = btrfs::tree_name(root).map_or_else(|| (root as i64).to_string(), |name| name.to_owned());
+                        sample_tree.add([category, name.as_str()].into_iter());
+                    },
+                    Ok(Some(btrfs::TreeBlockOwner::Shared)) => {
+                        sample_tree.add([category, "<shared>"].into_iter());
+                    },
+                    // space of the chunk not allocated to a tree block
+                    Ok(None) => {
+                        sample_tree.add([category, "<unused>"].into_iter());
+                    },
+                    Err(_) => {
+                        add_error(sample_tree, errors, errors_in_tree, "TREE_BLOCK_LOOKUP");
+                    },
+                }
+            },
+            btrfs::BTRFS_BLOCK_GROUP_METADATA => {
+                sample_tree.add([SampleCategory::Metadata.label(random_chunk.chunk_type, args.profiles).as_ref()].into_iter());
+            },
+            btrfs::BTRFS_BLOCK_GROUP_SYSTEM => {
+                sample_tree.add([SampleCategory::System.label(random_chunk.chunk_type, args.profiles).as_ref()].into_iter());
+            },
+            _ => {
+                add_error(sample_tree, errors, errors_in_tree, "UNKNOWN_CHUNK_TYPE");
+            }
+        };
+    }
+}
+
+// Malformed items found by a lookup are counted on their own, apart from failed lookups
+fn lookup_error_kind(err: &anyhow::Error, kind: &'static str) -> &'static str {
+    if err.is::<btrfs::MalformedItem>() {
+        "MALFORMED_ITEM"
+    } else {
+        kind
+    }
+}
+
+// A line of --inspect-dump, like btrfs inspect-internal logical-resolve prints them
+fn inspect_line(logical: u64, path: &[Cow<str>]) -> String {
+    format!("{} -> /{}\n", logical, path.join("/"))
+}
+
+// Group of --age-split for data written in `extent_generation`, unknown when the extent couldn't be found
+fn age_bucket(current_generation: u64, extent_generation: Option<u64>, max_age: u64) -> &'static str {
+    match extent_generation {
+        Some(extent_generation) if current_generation.saturating_sub(extent_generation) <= max_age => "[recent]",
+        Some(_) => "[old]",
+        None => "[unknown age]",
+    }
+}
+
+// Positions to pick samples from. Uniform panics on an empty range, which is what a filesystem
+// without chunks (or with all of them filtered out) would give.
+fn sample_range(total_chunk_length: u64) -> Result<Uniform<u64>> {
+    if total_chunk_length == 0 {
+        anyhow::bail!("no chunks to sample, the filesystem has none allocated yet or --data-only/--metadata-only left out all of them");
+    }
+    Ok(Uniform::new(0, total_chunk_length))
+}
+
+// Path of one of the names of a file with hardlinks, picked at random so that over many
+// samples its usage is spread evenly across them. None for files with a single name.
+fn random_link(fd: i32, root: u64, inum: u64, rng: &mut StdRng) -> Option<String> {
+    let links = btrfs::inode_links(fd, root, inum).ok()?;
+    if links.len() < 2 {
+        return None;
+    }
+    let (parent, name) = &links[rng.gen_range(0..links.len())];
+    let dir = btrfs::ino_lookup_sync(fd, root, *parent).ok()?;
+    Some(format!("{}{}", dir, name))
+}
+
+// Progress of the printed mode, reported on stderr while sampling
+struct Stats {
+    quiet: bool,
+    // -v logs the progress instead of the status line
+    log: bool,
+    tty: bool,
+    // --follow redraws the whole tree on stdout instead
+    follow: Option<PrintOptions>,
+    shown: RefCell<ShownPaths>,
+    last_report: Instant,
+    last_samples: usize,
+}
+
+impl Stats {
+    fn new(total_samples: usize, follow: Option<PrintOptions>, quiet: bool, log: bool) -> Self {
+        Self {
+            quiet,
+            log,
+            tty: nix::unistd::isatty(libc::STDERR_FILENO).unwrap_or(false),
+            follow,
+            shown: RefCell::default(),
+            last_report: Instant::now(),
+            last_samples: total_samples,
+        }
+    }
+
+    // A terminal gets one line rewritten every second, logs get a new line every 10 seconds
+    fn update(&mut self, profile: &Profile) -> Result<()> {
+        if self.quiet && self.follow.is_none() && !self.log {
+            return Ok(());
+        }
+        let elapsed = self.last_report.elapsed();
+        if elapsed < Duration::from_secs(if self.tty || self.follow.is_some() || self.log { 1 } else { 10 }) {
+            return Ok(());
+        }
+        let rate = (profile.total_samples - self.last_samples) as f64 / elapsed.as_secs_f64();
+        let status = render_status(profile, rate);
+        if let Some(opts) = &self.follow {
+            // cursor home and clear screen, like watch
+            let mut buf = String::from("\x1b[H\x1b[2J");
+            profile.tree.print_shown(&mut buf, profile.total_samples, profile.total_length, opts, Some(&self.shown))?;
+            buf.push_str(&status);
+            buf.push('\n');
+            std::io::stdout_locked().write_all(buf.as_bytes())?;
+        } else if self.log {
+            debug!("{}", status);
+        } else if self.tty {
+            eprint!("\r{}\x1b[K", status);
+        } else {
+            eprintln!("{}", status);
+        }
+        self.last_report = Instant::now();
+        self.last_samples = profile.total_samples;
+        Ok(())
+    }
+
+    // clears the status line (or the last redraw) so it doesn't end up in front of the report
+    fn finish(&self) {
+        if self.follow.is_some() {
+            print!("\x1b[H\x1b[2J");
+        } else if self.tty && !self.quiet && !self.log {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+fn print_options(args: &Args, pins: Vec<Vec<String>>) -> PrintOptions {
+    PrintOptions {
+        min_disk_fraction: Some(args.min_pct / 100.0),
+        collapse_single_child: args.collapse_single_child,
+        pins,
+        // a file isn't limited to the terminal's width
+        max_width: if args.output.is_some() { None } else { terminal_width() },
+        max_depth: args.depth,
+        color: match args.color.unwrap_or(ColorChoice::Auto) {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            // see https://no-color.org
+            ColorChoice::Auto => args.output.is_none() && terminal_width().is_some() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+        },
+    }
+}
+
+
+// Warnings by default, RUST_LOG takes precedence over -q and -v
+fn init_logging(args: &Args) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::WARN,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::default().add_directive(level.into()));
+    // the browser draws over stderr, warnings logged before it starts can still be read after it ends
+    let max_level = if args.interactive() { LevelFilter::WARN } else { LevelFilter::TRACE };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr).without_time().with_target(false))
+        .with(filter)
+        .with(max_level)
+        .init();
+}
+
+fn render_status(profile: &Profile, rate: f64) -> String {
+    let total_samples = profile.total_samples.max(1);
+    let errors = profile.errors.total();
+    format!("samples={} rate={:.0}/s errors={:.1}% resolution={}",
+        format_count(profile.total_samples), rate, 100.0 * errors as f64 / total_samples as f64, bytesize::to_string(profile.total_length / total_samples as u64, true))
+}
+
+// Set by the first SIGINT. The handler is reset when it runs, so a second one terminates
+// the process as usual, e.g. when an ioctl is stuck. Interrupted ioctls and writes are
+// restarted rather than failing with EINTR.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+fn install_sigint_handler() -> Result<()> {
+    let action = SigAction::new(SigHandler::Handler(handle_sigint), SaFlags::SA_RESETHAND | SaFlags::SA_RESTART, SigSet::empty());
+    unsafe {
+        signal::sigaction(Signal::SIGINT, &action)?;
+    }
+    Ok(())
+}
+
+fn raw_search(out: &mut impl Write, search: btrfs::SearchItems, tree_id: u64, objectid: Option<u64>, typ: Option<u8>) -> Result<()> {
+    let range = match (objectid, typ) {
+        (Some(objectid), Some(typ)) => btrfs::SearchKey::range_fixed_id_type(objectid, typ),
+        (Some(objectid), None) => btrfs::SearchKey::range_fixed_id(objectid),
+        (None, _) => btrfs::SearchKey::ALL,
+    };
+    let mut res = Ok(());
+    search(tree_id, range, &mut |sh, data| {
+        // without a fixed objectid the key range spans all types
+        if res.is_err() || typ.is_some_and(|typ| sh.type_ != typ as u32) {
+            return;
+        }
+        res = writeln!(out, "objectid={} type={} offset={} len={}\n{}", sh.objectid, sh.type_, sh.offset, sh.len, pretty_hex::pretty_hex(&data));
+    })?;
+    Ok(res?)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    init_logging(&args);
+    if !args.merge.is_empty() {
+        return merge_profiles(&args);
+    }
+    // let args: Vec<_> = env::args().collect();
+    let fd = fcntl::open(args.path.as_deref().unwrap_or_default(), OFlag::O_RDONLY, Mode::empty())?;
+    // let samples = args[2].as_str().parse::<usize>()?;
+    // fails right away on other filesystems, unlike the searches that need root
+    btrfs::fs_info(fd)?;
+
+    if let Some(tree_id) = args.raw_search {
+        let mut out = open_output(&args)?;
+        raw_search(&mut out, &|tree_id, range, cb| btrfs::tree_search_cb(fd, tree_id, range, cb), tree_id, args.raw_objectid, args.raw_type)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    if args.csum_usage {
+        let fs_info = btrfs::fs_info(fd)?;
+        let usage = btrfs::csum_usage(fd, &fs_info)?;
+        let mut out = open_output(&args)?;
+        writeln!(out, "csum={} checksummed={} csum_size={}", bytesize::to_string(usage.csum_bytes, true), bytesize::to_string(usage.checksummed_bytes, true), fs_info.csum_size)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    if args.chunks {
+        let chunks = read_chunks(fd, &args)?;
+        let mut out = open_output(&args)?;
+        print_chunks(&mut out, &chunks)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    if let Some(root_id) = args.subvol_size {
+        let size = btrfs::subvol_size(fd, root_id)?;
+        let exclusive = size.exclusive.map_or("unknown".to_owned(), |b| bytesize::to_string(b, true));
+        let mut out = open_output(&args)?;
+        writeln!(out, "referenced={} exclusive={}", bytesize::to_string(size.referenced, true), exclusive)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+
+
+    let mut sampler = Sampler::new(fd, &args)?;
+    let samples = match args.resolution {
+        Some(resolution) => {
+            let samples = sampler.total_chunk_length / resolution;
+            if samples == 0 {
+                warn!("--resolution {} is larger than the filesystem ({}), taking one sample",
+                    bytesize::to_string(resolution, true), bytesize::to_string(sampler.total_chunk_length, true));
+            }
+            samples.max(1)
+        },
+        None => args.samples,
+    };
+    let pins = parse_pins(&args);
+
+    let profile = Arc::new(Mutex::new(Profile {
+        total_length: sampler.total_chunk_length,
+        ..match &args.load {
+            Some(path) => Profile::load(path)?,
+            None => Profile::default(),
+        }
+    }));
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = args.metrics_addr {
+        metrics::spawn(addr, Arc::clone(&profile), sampler.total_chunk_length, Some(args.min_pct / 100.0))?;
+    }
+
+    let mut rate_limit = args.rate.map(RateLimit::new);
+    if args.interactive() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let ui = tui::spawn(Arc::clone(&profile), sampler.total_chunk_length, args.logical, Arc::clone(&stop));
+        let start = Instant::now();
+        // the lock is taken per sample so the browser never waits for more than one
+        while !stop.load(Ordering::Relaxed) {
+            // the browser stays open with what has been sampled
+            if args.duration.is_some_and(|duration| start.elapsed() >= duration) {
+                break;
+            }
+            // not while holding the lock, the browser would stall
+            if let Some(rate_limit) = &mut rate_limit {
+                rate_limit.wait();
+            }
+            let mut profile = profile.lock().unwrap();
+            if let Some(max_nodes) = args.max_nodes {
+                if profile.total_samples.is_multiple_of(1000) {
+                    profile.tree.limit_nodes(max_nodes, &pins);
+                }
+            }
+            if args.prune && profile.total_samples.is_multiple_of(10000) {
+                profile.prune(args.min_pct, &pins);
+            }
+            sampler.sample(&mut profile);
+            profile.total_samples += 1;
+        }
+        ui.join().unwrap()?;
+        if let Some(path) = &args.save {
+            profile.lock().unwrap().save(path)?;
+        }
+        return Ok(());
+    }
+
+    install_sigint_handler()?;
+    // created before sampling so that a bad path doesn't waste a run
+    let mut out = open_output(&args)?;
+    let loaded_samples = profile.lock().unwrap().total_samples;
+    let start = Instant::now();
+    // redrawing only makes sense when the report goes to the terminal
+    let follow = (args.follow && args.output.is_none() && terminal_width().is_some()).then(|| print_options(&args, pins.clone()));
+    let mut stats = Stats::new(loaded_samples, follow, args.quiet, args.verbose > 0);
+    for i in 0..samples {
+        if let Some(rate_limit) = &mut rate_limit {
+            rate_limit.wait();
+        }
+        let mut profile = profile.lock().unwrap();
+        if let Some(max_nodes) = args.max_nodes {
+            if i % 1000 == 0 {
+                profile.tree.limit_nodes(max_nodes, &pins);
+            }
+        }
+        if args.prune && i % 10000 == 0 {
+            profile.prune(args.min_pct, &pins);
+        }
+        sampler.sample(&mut profile);
+        profile.total_samples += 1;
+        stats.update(&profile)?;
+        // Ctrl-C or --duration stop early and print what has been sampled so far
+        if INTERRUPTED.load(Ordering::Relaxed) || args.duration.is_some_and(|duration| start.elapsed() >= duration) {
+            break;
+        }
+    }
+    let total_time = start.elapsed();
+    stats.finish();
+    let mut profile = profile.lock().unwrap();
+    if let Some(max_nodes) = args.max_nodes {
+        profile.tree.limit_nodes(max_nodes, &pins);
+    }
+    if let Some(path) = &args.save {
+        profile.save(path)?;
+    }
+
+    write_report(&mut out, &args, &mut sampler, &mut profile, pins, total_time, loaded_samples)?;
+    out.flush()?;
+    Ok(())
+}
+
+// What each copy of the data costs, for the legend of --profiles
+fn profile_description(profile: &str) -> &'static str {
+    match profile {
+        "single" => "one copy",
+        "dup" => "two copies on the same device",
+        "raid0" => "striped over devices, one copy",
+        "raid1" => "two copies on different devices",
+        "raid1c3" => "three copies on different devices",
+        "raid1c4" => "four copies on different devices",
+        "raid10" => "striped over mirrored pairs of devices, two copies",
+        "raid5" => "striped over devices with one device's worth of parity",
+        "raid6" => "striped over devices with two devices' worth of parity",
+        _ => "profile not known to btdu-rs",
+    }
+}
+
+fn parse_pins(args: &Args) -> Vec<Vec<String>> {
+    args.pin.iter().map(|p| p.split('/').filter(|s| !s.is_empty()).map(|s| s.to_owned()).collect()).collect()
+}
+
+// Where reports go, --output or stdout
+fn open_output(args: &Args) -> Result<Box<dyn Write>> {
+    Ok(match &args.output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path).with_context(|| format!("creating {}", path))?)),
+        None => Box::new(std::io::stdout_locked()),
+    })
+}
+
+fn merge_profiles(args: &Args) -> Result<()> {
+    let mut profile = Profile::default();
+    for path in &args.merge {
+        let file = std::fs::File::open(path).with_context(|| format!("opening {}", path))?;
+        profile.merge_from_reader(std::io::BufReader::new(file)).with_context(|| format!("merging {}", path))?;
+    }
+    if profile.total_samples == 0 {
+        anyhow::bail!("no samples in {}", args.merge.join(", "));
+    }
+    if let Some(path) = &args.save {
+        profile.save(path)?;
+    }
+
+    let mut out = open_output(args)?;
+    if args.json {
+        write_json(&mut out, args, &profile, profile.total_length, &Summary::new(&profile, profile.total_length, None))?;
+    } else {
+        let mut buf = String::new();
+        buf.push_str(&format!("merged={} total={}\n", args.merge.len(), bytesize::to_string(profile.total_length, true)));
+        buf.push_str(&format!("{}\n", Summary::new(&profile, profile.total_length, None)));
+        profile.tree.print(&mut buf, profile.total_samples, profile.total_length, &print_options(args, parse_pins(args)))?;
+        out.write_all(buf.as_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_json(out: &mut impl Write, args: &Args, profile: &Profile, total_length: u64, summary: &Summary) -> Result<()> {
+    let mut root = profile.tree.json_node("/", profile.total_samples, total_length, Some(args.min_pct / 100.0));
+    root.summary = Some(summary);
+    serde_json::to_writer(&mut *out, &root)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+// Change in bytes of every path since `baseline`, or its size without one. Changes smaller than
+// min_pct of the total length are left out, the largest growth comes first.
+fn changed_paths(profile: &Profile, baseline: Option<&Profile>, total_length: u64, min_pct: f64) -> Vec<(String, i64)> {
+    let empty = Profile::default();
+    let baseline = baseline.unwrap_or(&empty);
+    // a baseline taken before chunks were allocated or freed has different bytes per sample
+    let bytes_per_sample = total_length as f64 / profile.total_samples as f64;
+    let baseline_length = if baseline.total_length > 0 { baseline.total_length } else { total_length };
+    let baseline_bytes_per_sample = baseline_length as f64 / baseline.total_samples.max(1) as f64;
+    let mut deltas = Vec::new();
+    profile.tree.diff(&baseline.tree).collect_deltas("", bytes_per_sample, baseline_bytes_per_sample, &mut deltas);
+    let min_delta = (total_length as f64 * min_pct / 100.0) as u64;
+    deltas.retain(|(_, delta)| delta.unsigned_abs() >= min_delta);
+    deltas.sort_by(|(pa, da), (pb, db)| db.cmp(da).then_with(|| pa.cmp(pb)));
+    deltas
+}
+
+// A changed path of --json with --baseline or --incremental-output
+#[derive(Serialize)]
+struct JsonDelta<'a> {
+    path: &'a str,
+    delta_bytes: i64,
+}
+
+fn write_deltas(out: &mut impl Write, args: &Args, deltas: &[(String, i64)]) -> Result<()> {
+    if args.json {
+        let deltas: Vec<JsonDelta> = deltas.iter().map(|(path, delta)| JsonDelta { path, delta_bytes: *delta }).collect();
+        serde_json::to_writer(&mut *out, &deltas)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    let mut buf = String::new();
+    if args.csv {
+        buf.push_str("path,delta_bytes\n");
+        for (path, delta) in deltas {
+            buf.push_str(&format!("{},{}\n", csv_field(path), delta));
+        }
+    } else {
+        for (path, delta) in deltas {
+            buf.push_str(&format!("{:>17} {}\n", format_delta(*delta), path));
+        }
+    }
+    out.write_all(buf.as_bytes())?;
+    Ok(())
+}
+
+// Prints what changed since the profile the previous run saved at `path`, then saves this one there
+fn write_incremental(out: &mut impl Write, args: &Args, profile: &Profile, total_length: u64, path: &str) -> Result<()> {
+    let previous = if std::path::Path::new(path).exists() {
+        Some(Profile::load(path)?)
+    } else {
+        None
+    };
+    write_deltas(out, args, &changed_paths(profile, previous.as_ref(), total_length, args.min_pct))?;
+    profile.save(path)
+}
+
+// Removes the ERROR node from the tree and returns the samples and length that are left once
+// `error_samples` are taken out of the total. Bytes per sample stay the same.
+fn resolved_total(tree: &mut SampleTree, total_samples: usize, total_length: u64, error_samples: usize) -> (usize, u64) {
+    if let Some(errors) = tree.children.remove(SampleCategory::Error.as_str()) {
+        tree.total -= errors.total;
+    }
+    let resolved_samples = total_samples.saturating_sub(error_samples);
+    if resolved_samples == 0 {
+        return (0, 0);
+    }
+    let resolved_length = (total_length as f64 * resolved_samples as f64 / total_samples as f64) as u64;
+    (resolved_samples, resolved_length)
+}
+
+// The n inodes with the most hits, (root, inum) breaks ties so the order is stable
+fn top_inodes(stats: &HashMap<(u64, u64), u64>, n: usize) -> Vec<((u64, u64), u64)> {
+    let mut top: Vec<_> = stats.iter().map(|(&k, &v)| (k, v)).collect();
+    top.sort_by(|(ka,va), (kb,vb)| vb.cmp(va).then_with(|| ka.cmp(kb)));
+    top.truncate(n);
+    top
+}
+
+fn write_report(out: &mut impl Write, args: &Args, sampler: &mut Sampler, profile: &mut Profile, pins: Vec<Vec<String>>, total_time: Duration, loaded_samples: usize) -> Result<()> {
+    let mut total_chunk_length = sampler.total_chunk_length;
+    if args.inspect_dump {
+        out.write_all(sampler.inspect_lines.as_bytes())?;
+        return Ok(());
+    }
+
+    if let Some(path) = &args.baseline {
+        let baseline = Profile::load(path)?;
+        return write_deltas(out, args, &changed_paths(profile, Some(&baseline), total_chunk_length, args.min_pct));
+    }
+
+    if let Some(path) = &args.incremental_output {
+        return write_incremental(out, args, profile, total_chunk_length, path);
+    }
+
+    // the summary, errors and timing are of the whole run, also with --start-dir
+    let used = Some(sampler.used_length).filter(|&used| used > 0);
+    let summary = Summary::new(profile, total_chunk_length, used);
+    let taken_samples = profile.total_samples.saturating_sub(loaded_samples);
+
+    // The report covers --start-dir only: its samples become the total and the length is cut down
+    // to keep the bytes per sample, so sizes stay what they were and percentages are of the directory.
+    if !sampler.start_dir.is_empty() {
+        let dir_path = std::iter::once(SampleCategory::Data.as_str()).chain(sampler.start_dir.iter().map(|s| s.as_str()));
+        if profile.tree.total_at(dir_path.clone()) == 0 {
+            anyhow::bail!("no samples under /{}", sampler.start_dir.join("/"));
+        }
+        let mut node = std::mem::take(&mut profile.tree);
+        for segment in dir_path {
+            node = node.children.remove(segment).unwrap_or_default();
+        }
+        total_chunk_length = (total_chunk_length as u128 * node.total as u128 / profile.total_samples as u128) as u64;
+        profile.total_samples = node.total;
+        profile.tree = SampleTree {
+            total: node.total,
+            children: HashMap::from([(sampler.start_dir.join("/"), node)]),
+        };
+    }
+
+    if args.csv {
+        let mut buf = String::from("path,samples,fraction,disk_bytes\n");
+        profile.tree.write_csv(&mut buf, "", profile.total_samples, total_chunk_length, Some(args.min_pct / 100.0))?;
+        out.write_all(buf.as_bytes())?;
+        return Ok(());
+    }
+
+    if let Some(n) = args.top {
+        let mut nodes = Vec::new();
+        profile.tree.flatten("", args.top_leaves, &mut nodes);
+        nodes.sort_by(|(pa, ta), (pb, tb)| tb.cmp(ta).then_with(|| pa.cmp(pb)));
+        let mut buf = String::new();
+        for (path, total) in nodes.into_iter().take(n) {
+            let disk_fraction = total as f64 / profile.total_samples as f64;
+            buf.push_str(&format!("{:>16} {:>5.1}% {}\n", bytesize::to_string((total_chunk_length as f64 * disk_fraction) as u64, true), disk_fraction * 100.0, path));
+        }
+        out.write_all(buf.as_bytes())?;
+        return Ok(());
+    }
+
+    if args.folded {
+        let mut buf = String::new();
+        profile.tree.write_folded(&mut buf, "")?;
+        out.write_all(buf.as_bytes())?;
+        return Ok(());
+    }
+
+    if args.json {
+        return write_json(out, args, profile, total_chunk_length, &summary);
+    }
+
+    let mut buf = String::new();
+    buf.push_str(&format!("default_subvol={} path=/{}\n", sampler.default_subvol, sampler.roots.get_root(sampler.default_subvol)?.join("/")));
+    buf.push_str(&format!("{}\n", summary));
+    let print_opts = print_options(args, pins);
+    if args.exclude_errors_from_total {
+        // errors have no path, so none of them are below --start-dir
+        let error_samples = if sampler.start_dir.is_empty() { profile.errors.total() } else { 0 };
+        let (resolved_samples, resolved_length) = resolved_total(&mut profile.tree, profile.total_samples, total_chunk_length, error_samples);
+        if resolved_samples > 0 {
+            profile.tree.print(&mut buf, resolved_samples, resolved_length, &print_opts)?;
+        }
+        buf.push_str(&format!("coverage={:.1}% errors={}\n", 100.0 * resolved_samples as f64 / profile.total_samples as f64, error_samples));
+    } else {
+        profile.tree.print(&mut buf, profile.total_samples, total_chunk_length, &print_opts)?;
+    }
+    out.write_all(buf.as_bytes())?;
+    if args.profiles {
+        let profiles: BTreeMap<&str, &str> = sampler.chunks.iter()
+            .map(|c| (btrfs::profile_name(c.chunk_type), profile_description(btrfs::profile_name(c.chunk_type))))
+            .collect();
+        for (name, description) in profiles {
+            writeln!(out, "[{}] {}", name, description)?;
+        }
+    }
+
+    let resolution = total_chunk_length / profile.total_samples as u64;
+    let path_cache = &sampler.path_cache;
+    writeln!(out, "elapsed={:?} per_sample={:?} resolution={} path_cache_hits={:.1}% path_cache={}/{}", total_time, total_time/(taken_samples.max(1) as u32), bytesize::to_string(resolution, true),
+        100.0 * path_cache.hit_rate(), path_cache.paths.len(), path_cache.capacity)?;
+    if let (Some(used), Some(ratio)) = (summary.used, summary.ratio) {
+        writeln!(out, "used={} estimated={} ratio={:.3}", bytesize::to_string(used, true), bytesize::to_string(summary.estimated, true), ratio)?;
+    }
+    if profile.errors.total() > 0 {
+        let kinds: Vec<String> = profile.errors.kinds.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        writeln!(out, "errors={} unresolved_inodes={} {}", profile.errors.total(), sampler.unresolved_inodes.len(), kinds.join(" "))?;
+    }
+
+    if let Some(n) = args.top_inodes {
+        writeln!(out, "top inodes:")?;
+        // paths are only resolved for the reported inodes
+        for ((root, inum), hits) in top_inodes(&sampler.inode_stats, n) {
+            let root_path = sampler.roots.get_root(root)?;
+            let mut path = String::new();
+            for p in root_path.iter() {
+                path.push('/');
+                path.push_str(p);
+            }
+            match btrfs::ino_lookup_sync(sampler.fd, root, inum) {
+                Ok(inode_path) => {
+                    path.push('/');
+                    path.push_str(inode_path.trim_end_matches('/'));
+                },
+                Err(_) => {
+                    path.push_str(&format!("/<root {} inode {}>", root, inum));
+                },
+            }
+            writeln!(out, "{:>8} {:>16} {}", hits, bytesize::to_string(hits * resolution, true), path)?;
+        }
+    }
+
+    if args.dedup_estimate {
+        let fs_info = btrfs::fs_info(sampler.fd)?;
+        let estimate = dedup::estimate(sampler.fd, &fs_info)?;
+        writeln!(out, "dedup estimate (heuristic, by data checksum): duplicate={} checksummed={}",
+            bytesize::to_string(estimate.duplicate_bytes, true), bytesize::to_string(estimate.checksummed_bytes, true))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_splits_first_level() {
+        let mut profile = Profile::default();
+        profile.tree.add(["DATA", "home", "file"].into_iter());
+        profile.tree.add(["DATA", "UNREACHABLE"].into_iter());
+        profile.tree.add(["METADATA"].into_iter());
+        add_error(&mut profile.tree, &mut profile.errors, false, "INO_LOOKUP");
+        profile.total_samples = 4;
+        let summary = Summary::new(&profile, 4096, Some(6144));
+        assert_eq!((summary.data, summary.metadata, summary.system, summary.errors, summary.unreachable), (2048, 1024, 0, 1024, 1024));
+        // the error sample is left out of the estimate
+        assert_eq!((summary.used, summary.estimated, summary.ratio), (Some(6144), 3072, Some(0.5)));
+    }
+
+    #[test]
+    fn merge_rescales_to_smaller_bytes_per_sample() {
+        let mut profile = Profile::default();
+        for (name, total_length) in [("a", 1000), ("b", 2000)] {
+            let mut other = Profile { total_length, total_samples: 10, ..Profile::default() };
+            other.tree.add_samples(["DATA", name].into_iter(), 10);
+            profile.merge_from_reader(serde_json::to_vec(&other).unwrap().as_slice()).unwrap();
+        }
+        assert_eq!((profile.total_samples, profile.total_length), (30, 3000));
+        assert_eq!(profile.tree.get(["DATA", "a"].into_iter()).unwrap().total, 10);
+        assert_eq!(profile.tree.get(["DATA", "b"].into_iter()).unwrap().total, 20);
+    }
+
+    #[test]
+    fn merge_keeps_parents_at_least_their_children() {
+        fn check(tree: &SampleTree) {
+            assert!(tree.total >= tree.children.values().map(|c| c.total).sum::<usize>());
+            tree.children.values().for_each(check);
+        }
+        let mut profile = Profile::default();
+        // 100 and 150 bytes per sample, the second profile is scaled by 1.5
+        for (names, total_length) in [(&["x"][..], 100), (&["a", "b", "c"][..], 450)] {
+            let mut other = Profile { total_length, total_samples: names.len(), ..Profile::default() };
+            for name in names {
+                other.tree.add(["DATA", "dir", name].into_iter());
+            }
+            profile.merge_from_reader(serde_json::to_vec(&other).unwrap().as_slice()).unwrap();
+        }
+        check(&profile.tree);
+        assert_eq!(profile.total_samples, profile.tree.total);
+        let mut folded = String::new();
+        profile.tree.write_folded(&mut folded, "").unwrap();
+        let folded_total: usize = folded.lines().map(|l| l.rsplit(' ').next().unwrap().parse::<usize>().unwrap()).sum();
+        assert_eq!(folded_total, profile.tree.total);
+    }
+
+    #[test]
+    fn summary_adds_up_profiles() {
+        let mut profile = Profile::default();
+        profile.tree.add(["DATA[raid1]", "file"].into_iter());
+        profile.tree.add(["DATA[single]", "UNREACHABLE"].into_iter());
+        profile.tree.add(["METADATA[dup]"].into_iter());
+        profile.tree.add(["DATAX"].into_iter());
+        profile.tree.add(["FREE[single]", "DATA"].into_iter());
+        profile.total_samples = 5;
+        let summary = Summary::new(&profile, 5120, None);
+        assert_eq!((summary.data, summary.metadata, summary.free, summary.unreachable), (2048, 1024, 1024, 1024));
+    }
+
+    #[test]
+    fn profile_labels() {
+        let flags = (btrfs::BTRFS_BLOCK_GROUP_DATA | btrfs::BTRFS_BLOCK_GROUP_RAID1C3) as u64;
+        assert_eq!(SampleCategory::Data.label(flags, true), "DATA[raid1c3]");
+        assert_eq!(SampleCategory::Data.label(flags, false), "DATA");
+        let flags = (btrfs::BTRFS_BLOCK_GROUP_METADATA | btrfs::BTRFS_BLOCK_GROUP_RAID1C4) as u64;
+        assert_eq!(SampleCategory::Metadata.label(flags, true), "METADATA[raid1c4]");
+        assert_eq!(profile_description("raid1c3"), "three copies on different devices");
+        assert_eq!(profile_description("raid1c4"), "four copies on different devices");
+    }
+
+    #[test]
+    fn truncated_directory_keeps_last_name() {
+        let mut tree = SampleTree::new();
+        for file in ["a.jpg", "b.jpg"] {
+            tree.add(["DATA", "a_rather_long_directory_name", "photos", file].into_iter());
+        }
+        let opts = PrintOptions { collapse_single_child: true, max_width: Some(50), ..PrintOptions::default() };
+        let mut out = String::new();
+        tree.print(&mut out, tree.total, 4096, &opts).unwrap();
+        let row = out.lines().next().unwrap();
+        assert!(row.starts_with("/DATA/a_rather…/photos/ "), "{}", row);
+    }
+
+    #[test]
+    fn truncate_middle_counts_columns() {
+        let name = "/写真/日本語のとても長いファイル名.txt";
+        assert_eq!(truncate_middle(name, 40), name);
+        assert_eq!(truncate_middle(name, 20), "/写真/日本…ル名.txt");
+        assert_eq!(truncate_middle("/photos/🎉🎉🎉🎉🎉🎉🎉🎉🎉🎉.png", 20), "/photos/🎉…🎉🎉.png");
+        assert_eq!(pad_width("日本", 6), "日本  ");
+    }
+
+    #[test]
+    fn json_output_nests_summary_in_root() {
+        let mut profile = Profile { total_samples: 2, ..Profile::default() };
+        profile.tree.add(["DATA", "a \"quoted\" name"].into_iter());
+        profile.tree.add(["METADATA"].into_iter());
+        let args = Args::parse_from(["btdu-rs", "--min-pct", "0", "/"]);
+        let mut out = Vec::new();
+        write_json(&mut out, &args, &profile, 2048, &Summary::new(&profile, 2048, None)).unwrap();
+        let root: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!((root["name"].as_str(), root["total"].as_u64()), (Some("/"), Some(2)));
+        assert_eq!(root["summary"]["metadata"].as_u64(), Some(1024));
+        assert_eq!(root["summary"]["estimated"].as_u64(), Some(2048));
+        assert!(root["summary"].get("ratio").is_none());
+        assert_eq!(root["children"][0]["children"][0]["name"].as_str(), Some("a \"quoted\" name"));
+        assert!(root["children"][0].get("summary").is_none());
+    }
+
+    #[test]
+    fn add_samples_prints_like_repeated_adds() {
+        let mut single = SampleTree::new();
+        for _ in 0..3 {
+            single.add(["DATA", "home", "a"].into_iter());
+        }
+        single.add(["DATA", "home", "b"].into_iter());
+        single.add(["METADATA"].into_iter());
+        let mut mixed = SampleTree::new();
+        mixed.add_samples(["DATA", "home", "a"].into_iter(), 2);
+        mixed.add(["DATA", "home", "a"].into_iter());
+        mixed.add(["DATA", "home", "b"].into_iter());
+        mixed.add_samples(["METADATA"].into_iter(), 1);
+        mixed.add_samples(["DATA", "empty"].into_iter(), 0);
+        assert!(mixed.get(["DATA", "empty"].into_iter()).is_none());
+        let opts = PrintOptions::default();
+        let (mut a, mut b) = (String::new(), String::new());
+        single.print(&mut a, 5, 5120, &opts).unwrap();
+        mixed.print(&mut b, 5, 5120, &opts).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn get_and_total_at_paths() {
+        let mut tree = SampleTree::new();
+        tree.add_samples(["DATA", "home", "user"].into_iter(), 3);
+        tree.add(["DATA", "etc"].into_iter());
+        assert_eq!(tree.get(["DATA", "home", "user"].into_iter()).map(|n| n.total), Some(3));
+        assert_eq!(tree.total_at(["DATA", "home", "user"].into_iter()), 3);
+        assert_eq!(tree.total_at(["DATA"].into_iter()), 4);
+        // a missing segment in the middle or at the end
+        assert!(tree.get(["DATA", "var", "user"].into_iter()).is_none());
+        assert_eq!(tree.total_at(["DATA", "var", "user"].into_iter()), 0);
+        assert_eq!(tree.total_at(["DATA", "etc", "passwd"].into_iter()), 0);
+        // the empty path is the root
+        assert!(std::ptr::eq(tree.get(std::iter::empty()).unwrap(), &tree));
+        assert_eq!(tree.total_at(std::iter::empty()), 4);
+    }
+
+    // (indentation, count) of every printed row
+    fn printed_rows(out: &str) -> Vec<(usize, usize)> {
+        out.lines().map(|line| {
+            let depth = line.len() - line.trim_start().len();
+            // count, percentage, size and its unit are the last columns
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            (depth, columns[columns.len() - 4].replace(',', "").parse().unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn others_row_adds_up_hidden_children() {
+        let mut tree = SampleTree::new();
+        tree.add_samples(["DATA", "big"].into_iter(), 50);
+        for i in 0..20 {
+            tree.add(["DATA", "small", &format!("file{}", i)].into_iter());
+            tree.add(["DATA", &format!("tiny{}", i)].into_iter());
+        }
+        tree.add_samples(["METADATA"].into_iter(), 10);
+        // samples folded by --max-nodes or --prune share the row of the hidden children,
+        // or get one of their own
+        tree.add_samples(["DATA", OTHER_NODE].into_iter(), 3);
+        tree.add_samples(["DATA", "folded", OTHER_NODE].into_iter(), 10);
+        let opts = PrintOptions { min_disk_fraction: Some(0.05), ..PrintOptions::default() };
+        let mut out = String::new();
+        tree.print(&mut out, tree.total, 1 << 20, &opts).unwrap();
+        assert!(out.contains(" <others: 20 items> "), "{}", out);
+        assert!(out.lines().any(|line| line.trim_start().starts_with("<others> ")), "{}", out);
+        let rows = printed_rows(&out);
+        assert_eq!(rows.iter().filter(|(depth, _)| *depth == 0).map(|(_, count)| count).sum::<usize>(), tree.total);
+        for (i, &(depth, count)) in rows.iter().enumerate() {
+            let children: Vec<usize> = rows[i + 1..].iter()
+                .take_while(|(d, _)| *d > depth)
+                .filter(|(d, _)| *d == depth + 1)
+                .map(|(_, count)| *count)
+                .collect();
+            if !children.is_empty() {
+                assert_eq!(children.iter().sum::<usize>(), count, "{}", out);
+            }
+        }
+    }
+
+    #[test]
+    fn pinned_paths_are_printed_below_min_pct() {
+        let mut tree = SampleTree::new();
+        tree.add_samples(["DATA", "big"].into_iter(), 90);
+        tree.add_samples(["DATA", "small1"].into_iter(), 2);
+        tree.add_samples(["DATA", "small2"].into_iter(), 3);
+        tree.add_samples(["DATA", "pinned"].into_iter(), 1);
+        let pins = vec![
+            vec!["DATA".to_owned(), "pinned".to_owned()],
+            vec!["DATA".to_owned(), "missing".to_owned()],
+        ];
+        let opts = PrintOptions { min_disk_fraction: Some(0.05), pins, ..PrintOptions::default() };
+        let mut out = String::new();
+        tree.print(&mut out, tree.total, 1 << 20, &opts).unwrap();
+        let paths: Vec<&str> = out.lines().map(|line| line[..60].trim_end()).collect();
+        // a pin that has no samples is shown empty, unpinned siblings below min_pct are folded
+        assert_eq!(paths, ["/DATA/", " /big", " /pinned", " /missing", " <others: 2 items>"], "{}", out);
+        assert_eq!(printed_rows(&out), [(0, 96), (1, 90), (1, 1), (1, 0), (1, 5)]);
+    }
+
+    #[test]
+    fn follow_keeps_paths_close_to_min_pct() {
+        let opts = PrintOptions { min_disk_fraction: Some(0.1), ..PrintOptions::default() };
+        let shown = RefCell::default();
+        // whether DATA/a is printed in a redraw where it has `a` of 100 samples
+        let redraw = |a: usize| {
+
+            let mut tree = SampleTree::new();
+            tree.add_samples(["DATA", "a"].into_iter(), a);
+            tree.add_samples(["DATA", "b"].into_iter(), 100 - a);
+            let mut out = String::new();
+            tree.print_shown(&mut out, tree.total, 1 << 20, &opts, Some(&shown)).unwrap();
+            out.lines().any(|line| line.trim_start().starts_with("/a "))
+        };
+        // the first redraw uses --min-pct as it is
+        assert!(!redraw(9));
+        // a hidden path appears at 12%, a shown one stays down to 8%
+        assert!(!redraw(11));
+        assert!(redraw(13));
+        assert!(redraw(9));
+        assert!(redraw(11));
+        assert!(!redraw(7));
+        assert!(!redraw(11));
+    }
+
+    #[test]
+    fn print_does_not_depend_on_insertion_order() {
+        let paths: Vec<[String; 3]> = (0..50).map(|i| ["DATA".to_owned(), format!("dir{}", i % 7), format!("file{}", i)]).collect();
+        let mut forward = SampleTree::new();
+        for path in &paths {
+            forward.add(path.iter().map(|s| s.as_str()));
+        }
+        let mut backward = SampleTree::new();
+        for path in paths.iter().rev() {
+            backward.add(path.iter().map(|s| s.as_str()));
+        }
+        let opts = PrintOptions::default();
+        let (mut a, mut b) = (String::new(), String::new());
+        forward.print(&mut a, 50, 1 << 20, &opts).unwrap();
+        backward.print(&mut b, 50, 1 << 20, &opts).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn collapse_single_child_chains() {
+        let mut tree = SampleTree::new();
+        for file in ["a.so", "b.so"] {
+            tree.add(["DATA", "usr", "lib", "x86_64", file].into_iter());
+        }
+        // opt has samples of its own, so it is not merged with its only child
+        tree.add(["DATA", "opt"].into_iter());
+        tree.add(["DATA", "opt", "app", "bin"].into_iter());
+        let opts = PrintOptions { collapse_single_child: true, ..PrintOptions::default() };
+        let mut out = String::new();
+        tree.print(&mut out, tree.total, 4096, &opts).unwrap();
+        // the path column is 60 wide without max_width
+        let paths: Vec<&str> = out.lines().map(|line| line[..60].trim_end()).collect();
+        assert_eq!(paths, ["/DATA/", " /opt/", "  /app/bin", " /usr/lib/x86_64/", "  /a.so", "  /b.so"]);
+    }
+
+    #[test]
+    fn limit_nodes_stays_under_cap() {
+        // a folded "<others>" leaf leaves its samples with its parent
+        fn check_totals(tree: &SampleTree) {
+            assert!(tree.children.values().map(|c| c.total).sum::<usize>() <= tree.total);
+            tree.children.values().for_each(check_totals);
+        }
+        let mut tree = SampleTree::new();
+        let pins = vec![vec!["DATA".to_owned(), "dir0".to_owned(), "file0".to_owned()]];
+        // the smallest leaves of all, but categories
+        tree.add(["METADATA"].into_iter());
+        tree.add(["SYSTEM"].into_iter());
+        for i in 0..2000 {
+            tree.add(["DATA", &format!("dir{}", i % 10), &format!("file{}", i)].into_iter());
+            if i % 100 == 0 {
+                tree.limit_nodes(50, &pins);
+            }
+        }
+        tree.limit_nodes(50, &pins);
+        assert!(tree.node_count() <= 50, "{} nodes", tree.node_count());
+        assert_eq!((tree.total, tree.total_at(["DATA"].into_iter())), (2002, 2000));
+        assert_eq!((tree.total_at(["METADATA"].into_iter()), tree.total_at(["SYSTEM"].into_iter())), (1, 1));
+        check_totals(&tree);
+        assert!(tree.get(pins[0].iter().map(|s| s.as_str())).is_some());
+    }
+
+    #[test]
+    fn prune_drops_only_small_subtrees() {
+        let mut profile = Profile::default();
+        profile.tree.add_samples(["DATA", "big", "file"].into_iter(), 90);
+        profile.tree.add_samples(["DATA", "big", "small"].into_iter(), 2);
+        profile.tree.add_samples(["DATA", "medium"].into_iter(), 5);
+        profile.tree.add_samples(["DATA", "pinned"].into_iter(), 1);
+        profile.tree.add_samples(["METADATA"].into_iter(), 2);
+        profile.total_samples = 100;
+        let pins = vec![vec!["DATA".to_owned(), "pinned".to_owned()]];
+        // subtrees below a tenth of --min-pct go, 3 of the 100 samples here
+        profile.prune(30.0, &pins);
+        let tree = &profile.tree;
+        assert_eq!((tree.total, tree.total_at(["DATA"].into_iter()), tree.total_at(["DATA", "big"].into_iter())), (100, 98, 92));
+        assert_eq!(tree.total_at(["DATA", "big", "file"].into_iter()), 90);
+        assert_eq!(tree.total_at(["DATA", "medium"].into_iter()), 5);
+        assert_eq!(tree.total_at(["DATA", "pinned"].into_iter()), 1);
+        assert!(tree.get(["DATA", "big", "small"].into_iter()).is_none());
+        assert_eq!(tree.total_at(["DATA", "big", OTHER_NODE].into_iter()), 2);
+        // categories are never folded, however small
+        assert_eq!(tree.total_at(["METADATA"].into_iter()), 2);
+        assert!(tree.get([OTHER_NODE].into_iter()).is_none());
+    }
+
+    #[test]
+    fn category_names() {
+        let names: Vec<&str> = SampleCategory::ALL.iter().map(|c| c.as_str()).collect();
+        assert_eq!(names, ["DATA", "METADATA", "SYSTEM", "FREE", "ERROR"]);
+        for category in SampleCategory::ALL {
+            assert_eq!(SampleCategory::from_label(category.as_str()), Some(category));
+        }
+        assert_eq!(SampleCategory::from_label("DATA[raid1]"), Some(SampleCategory::Data));
+        assert_eq!(SampleCategory::from_label("DATAX"), None);
+    }
+
+    #[test]
+    fn rows_align_across_magnitudes() {
+        let mut tree = SampleTree::new();
+        for (name, n) in [("one", 1), ("thousand", 1000), ("million", 1_000_000), ("billion", 1_000_000_000)] {
+            tree.add_samples(["DATA", name].into_iter(), n);
+        }
+        for max_width in [None, Some(80)] {
+            let opts = PrintOptions { max_width, ..PrintOptions::default() };
+            let mut out = String::new();
+            tree.print(&mut out, tree.total, 1 << 40, &opts).unwrap();
+            assert!(out.contains(" 1,000,000,000 "), "{}", out);
+            let columns: HashSet<Option<usize>> = out.lines().map(|line| line.find('%')).collect();
+            assert_eq!(columns.len(), 1, "{}", out);
+        }
+    }
+
+    #[test]
+    fn get_root_survives_broken_backrefs() {
+        let mut roots = Roots::new(-1);
+        roots.find_backref = |_, root_id| Ok(match root_id {
+            256 => Some(("home".to_owned(), 5)),
+            257 => Some(("user".to_owned(), 256)),
+            // refers to itself
+            300 => Some(("self".to_owned(), 300)),
+            // 301 and 302 are each other's parent
+            301 => Some(("a".to_owned(), 302)),
+            302 => Some(("b".to_owned(), 301)),
+            303 => Some(("below_cycle".to_owned(), 301)),
+            _ => None,
+        });
+        assert_eq!(*roots.get_root(257).unwrap(), ["home", "user"]);
+        assert_eq!(*roots.get_root(300).unwrap(), ["UNRESOLVED_ROOT_300"]);
+        assert_eq!(*roots.get_root(303).unwrap(), ["UNRESOLVED_ROOT_301", "below_cycle"]);
+        assert!(roots.get_root(302).unwrap()[0].starts_with("UNRESOLVED_ROOT_"));
+        assert_eq!(*roots.get_root(999).unwrap(), ["UNRESOLVED_ROOT_999"]);
+    }
+
+    #[test]
+    fn chunk_at_matches_linear_scan() {
+        let mut chunks = Vec::new();
+        let mut pos = 0;
+        for (i, sample_length) in [1u64, 4096, 1 << 30, 7, 1, 256 << 20].into_iter().enumerate() {
+            chunks.push(ChunkInfo { pos, chunk_offset: i as u64 * (1 << 30), chunk_length: sample_length, sample_length, chunk_type: 1 });
+            pos += sample_length;
+        }
+        let linear = |pos: u64| chunks.iter().position(|c| pos >= c.pos && pos < c.pos + c.sample_length).unwrap();
+        // both ends of every chunk and some points in between
+        let mut positions: Vec<u64> = chunks.iter().flat_map(|c| [c.pos, c.pos + c.sample_length / 2, c.pos + c.sample_length - 1]).collect();
+        positions.extend((0..1000).map(|i| i * (pos / 1000)));
+        for p in positions {
+            assert_eq!(chunk_at(&chunks, p), linear(p), "pos {}", p);
+        }
+        assert_eq!(chunk_at(&chunks, pos), chunks.len());
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let mut tree = SampleTree::new();
+        tree.add_samples(["DATA", "a,b", "say \"hi\""].into_iter(), 6);
+        tree.add_samples(["DATA", "line\nbreak"].into_iter(), 3);
+        tree.add_samples(["DATA", "tiny"].into_iter(), 1);
+        tree.add_samples(["METADATA"].into_iter(), 10);
+        let mut out = String::from("path,samples,fraction,disk_bytes\n");
+        tree.write_csv(&mut out, "", 20, 20 * 1024, Some(0.1)).unwrap();
+
+        let mut reader = csv::Reader::from_reader(out.as_bytes());
+        assert_eq!(reader.headers().unwrap(), vec!["path", "samples", "fraction", "disk_bytes"]);
+        let rows: Vec<(String, usize, f64, u64)> = reader.records()
+            .map(|r| {
+                let r = r.unwrap();
+                (r[0].to_owned(), r[1].parse().unwrap(), r[2].parse().unwrap(), r[3].parse().unwrap())
+            })
+            .collect();
+        // every node at or above 10% but /DATA/tiny
+        let paths: Vec<&str> = rows.iter().map(|(path, ..)| path.as_str()).collect();
+        assert_eq!(paths, ["/DATA", "/DATA/a,b", "/DATA/a,b/say \"hi\"", "/DATA/line\nbreak", "/METADATA"]);
+        assert_eq!(rows.iter().filter(|(path, ..)| path.matches('/').count() == 1).map(|(_, samples, ..)| samples).sum::<usize>(), 20);
+        assert_eq!(rows[1], ("/DATA/a,b".to_owned(), 6, 0.3, 6 * 1024));
+    }
+
+    // An INODE_ITEM as stored on disk, uid and gid are at bytes 44 and 48, flags at 64
+    fn inode_item(uid: u32, gid: u32, flags: u64) -> btrfs::btrfs_inode_item {
+        let mut data = vec![0u8; std::mem::size_of::<btrfs::btrfs_inode_item>()];
+        data[44..48].copy_from_slice(&uid.to_le_bytes());
+        data[48..52].copy_from_slice(&gid.to_le_bytes());
+        data[64..72].copy_from_slice(&flags.to_le_bytes());
+        let sh = btrfs::btrfs_ioctl_search_header { transid: 0, objectid: 257, offset: 0, type_: btrfs::BTRFS_INODE_ITEM_KEY, len: data.len() as u32 };
+        btrfs::read_item(&sh, &data).unwrap()
+    }
+
+    #[test]
+    fn owners_from_inode_items() {
+        let mut owners = Owners::new(-1);
+        owners.find_inode_item = |_, _, inum| Ok(match inum {
+            // ids without a passwd or group entry, e.g. of a deleted user
+            257 => Some(inode_item(4_000_000_000, 4_000_000_001, 0)),
+            258 => Some(inode_item(0, 0, 0)),
+            _ => None,
+        });
+        assert_eq!(*owners.get_owner(5, 257), "4000000000:4000000001");
+        assert!(owners.get_owner(5, 258).starts_with("root:"));
+        assert_eq!(*owners.get_owner(5, 259), "UNKNOWN_OWNER");
+    }
+
+    #[test]
+    fn nocow_dirs_are_tagged() {
+        let mut nocow_dirs = NoCowDirs::new(-1);
+        // /a is NODATACOW, /a/b isn't and holds the file 259
+        nocow_dirs.find_inode_item = |_, _, inum| Ok(match inum {
+            257 => Some(inode_item(0, 0, btrfs::BTRFS_INODE_NODATACOW)),
+            256 | 258 => Some(inode_item(0, 0, 0)),
+            _ => None,
+        });
+        // the subvolume root directory 256 is its own parent
+        nocow_dirs.find_inode_parent = |_, _, inum| Ok(match inum {
+            256 | 257 => Some(256),
+            258 => Some(257),
+            259 => Some(258),
+            _ => None,
+        });
+        let path = |segments: &[&'static str]| -> Vec<Cow<str>> { segments.iter().map(|s| Cow::Borrowed(*s)).collect() };
+
+        let mut full_path = path(&["a", "b", "file"]);
+        nocow_dirs.tag(5, 259, 0, &mut full_path);
+        assert_eq!(full_path, path(&["a [nocow]", "b", "file"]));
+
+        // the same file in a subvolume at /subvol
+        let mut full_path = path(&["subvol", "a", "b", "file"]);
+        nocow_dirs.tag(5, 259, 1, &mut full_path);
+        assert_eq!(full_path, path(&["subvol", "a [nocow]", "b", "file"]));
+
+        // a path that doesn't match the parents, e.g. of another hardlink, is left as it is
+        let mut full_path = path(&["file"]);
+        nocow_dirs.tag(5, 259, 0, &mut full_path);
+        assert_eq!(full_path, path(&["file"]));
+    }
+
+    #[test]
+    fn incremental_output_over_two_runs() {
+        let path = std::env::temp_dir().join(format!("btdu-rs-incremental-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let total_length = 100 << 20;
+
+        // without a previous run everything above --min-pct is new
+        let args = Args::parse_from(["btdu-rs", "--min-pct", "5", "--incremental-output", path, "/"]);
+        let mut profile = Profile { total_samples: 100, total_length, ..Profile::default() };
+        profile.tree.add_samples(["DATA", "a"].into_iter(), 60);
+        profile.tree.add_samples(["DATA", "b"].into_iter(), 39);
+        profile.tree.add_samples(["DATA", "b", "tiny"].into_iter(), 1);
+        let mut out = Vec::new();
+        write_incremental(&mut out, &args, &profile, total_length, path).unwrap();
+        let paths: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().map(|l| l.rsplit(' ').next().unwrap()).collect();
+        assert_eq!(paths, ["/DATA", "/DATA/a", "/DATA/b"]);
+
+        // b shrinks by 10 MiB and c appears with as much, a and the total stay the same
+        let args = Args::parse_from(["btdu-rs", "--min-pct", "5", "--incremental-output", path, "--json", "/"]);
+        let mut profile = Profile { total_samples: 200, total_length, ..Profile::default() };
+        profile.tree.add_samples(["DATA", "a"].into_iter(), 120);
+        profile.tree.add_samples(["DATA", "b"].into_iter(), 60);
+        profile.tree.add_samples(["DATA", "c"].into_iter(), 20);
+        let mut out = Vec::new();
+        write_incremental(&mut out, &args, &profile, total_length, path).unwrap();
+        let deltas: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(deltas, serde_json::json!([
+            {"path": "/DATA/c", "delta_bytes": 10 << 20},
+            {"path": "/DATA/b", "delta_bytes": -(10 << 20)},
+        ]));
+        assert_eq!(Profile::load(path).unwrap().total_samples, 200);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn path_cache_is_bounded() {
+        let mut cache = PathCache::new(2);
+        let lookup = |inum: u64| move || Ok(format!("file{}", inum));
+        assert_eq!(&*cache.get_or_lookup((5, 1), lookup(1)).unwrap(), "file1");
+        assert_eq!(&*cache.get_or_lookup((5, 1), || anyhow::bail!("cached")).unwrap(), "file1");
+        // failures are counted as misses but not cached
+        assert!(cache.get_or_lookup((5, 2), || anyhow::bail!("ENOENT")).is_err());
+        cache.get_or_lookup((5, 2), lookup(2)).unwrap();
+        assert_eq!(cache.paths.len(), 2);
+        // a full cache starts over
+        cache.get_or_lookup((5, 3), lookup(3)).unwrap();
+        assert_eq!(cache.paths.len(), 1);
+        assert_eq!((cache.hits, cache.misses), (1, 4));
+        assert_eq!(cache.hit_rate(), 0.2);
+    }
+
+    #[test]
+    fn errors_excluded_from_total() {
+        for errors_in_tree in [false, true] {
+            let mut profile = Profile { total_samples: 10, ..Profile::default() };
+            profile.tree.add_samples(["DATA", "a"].into_iter(), 6);
+            profile.tree.add_samples(["METADATA"].into_iter(), 2);
+            for _ in 0..2 {
+                add_error(&mut profile.tree, &mut profile.errors, errors_in_tree, "INO_LOOKUP");
+            }
+            // by default errors are part of the total
+            let data = profile.tree.total_at(["DATA"].into_iter());
+            assert_eq!(data as f64 / profile.total_samples as f64, 0.6);
+
+            let (samples, length) = resolved_total(&mut profile.tree, profile.total_samples, 10240, profile.errors.total());
+            assert_eq!((samples, length), (8, 8192));
+            assert_eq!((profile.tree.total, profile.tree.total_at(["ERROR"].into_iter())), (8, 0));
+            assert_eq!(data as f64 / samples as f64, 0.75);
+            // DATA keeps its size
+            assert_eq!(profile.tree.children["DATA"].total_bytes(length as f64 / samples as f64), 6144);
+        }
+
+        let mut tree = SampleTree::new();
+        add_error(&mut tree, &mut SampleErrors::default(), true, "INO_LOOKUP");
+        assert_eq!(resolved_total(&mut tree, 1, 1024, 1), (0, 0));
+    }
+
+    #[test]
+    fn inspect_lines_of_shared_extent() {
+        // an extent shared by a file and its snapshot resolves to both paths
+        let paths: [Vec<Cow<str>>; 2] = [
+            vec!["home".into(), "a b".into()],
+            vec!["snapshots".into(), "1".into(), "home".into(), "a b".into()],
+        ];
+        let out: String = paths.iter().map(|path| inspect_line(13631488, path)).collect();
+        let lines: Vec<(u64, &str)> = out.lines().map(|line| {
+            let (logical, path) = line.split_once(" -> ").unwrap();
+            (logical.parse().unwrap(), path)
+        }).collect();
+        assert_eq!(lines, [(13631488, "/home/a b"), (13631488, "/snapshots/1/home/a b")]);
+    }
+
+    #[test]
+    fn age_buckets() {
+        assert_eq!(age_bucket(1000, Some(1000), 100), "[recent]");
+        assert_eq!(age_bucket(1000, Some(900), 100), "[recent]");
+        assert_eq!(age_bucket(1000, Some(899), 100), "[old]");
+        // an extent can't be newer than the filesystem, but that must not underflow
+        assert_eq!(age_bucket(1000, Some(1001), 0), "[recent]");
+        assert_eq!(age_bucket(1000, None, 100), "[unknown age]");
+    }
+
+    #[test]
+    fn top_inodes_by_hits() {
+        let stats = HashMap::from([((5, 257), 3), ((5, 258), 10), ((256, 257), 3), ((5, 256), 1)]);
+        assert_eq!(top_inodes(&stats, 3), [((5, 258), 10), ((5, 257), 3), ((256, 257), 3)]);
+        assert_eq!(top_inodes(&stats, 10).len(), 4);
+        assert!(top_inodes(&stats, 0).is_empty());
+    }
+
+    #[test]
+    fn raw_search_filters_by_type() {
+        let chunk_tree = btrfs::BTRFS_CHUNK_TREE_OBJECTID as u64;
+        let items = [
+            btrfs::canned_item(chunk_tree, 1, btrfs::BTRFS_DEV_ITEM_KEY, 1, vec![0; 8]),
+            btrfs::canned_item(chunk_tree, 256, btrfs::BTRFS_CHUNK_ITEM_KEY, 1 << 20, vec![1; 8]),
+            btrfs::canned_item(chunk_tree, 256, btrfs::BTRFS_CHUNK_ITEM_KEY, 9 << 20, vec![2; 8]),
+            // another tree is never searched
+            btrfs::canned_item(btrfs::BTRFS_ROOT_TREE_OBJECTID as u64, 256, btrfs::BTRFS_CHUNK_ITEM_KEY, 0, vec![3; 8]),
+        ];
+        let search = |tree_id, range, cb: &mut dyn FnMut(&btrfs::btrfs_ioctl_search_header, &[u8])| btrfs::search_canned(&items, tree_id, range, cb);
+        let count = |objectid, typ| {
+            let mut out = Vec::new();
+            raw_search(&mut out, &search, chunk_tree, objectid, typ).unwrap();
+            String::from_utf8(out).unwrap().lines().filter(|line| line.starts_with("objectid=")).count()
+        };
+        assert_eq!(count(None, None), 3);
+        assert_eq!(count(None, Some(btrfs::BTRFS_CHUNK_ITEM_KEY as u8)), 2);
+        assert_eq!(count(Some(256), None), 2);
+        assert_eq!(count(Some(1), Some(btrfs::BTRFS_CHUNK_ITEM_KEY as u8)), 0);
+    }
+
+    #[test]
+    fn sample_range_rejects_no_chunks() {
+        assert!(sample_range(0).is_err());
+        assert!(sample_range(1).is_ok());
+    }
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("1048576"), Ok(1048576));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("4M"), Ok(4 * 1024 * 1024));
+        assert_eq!(parse_size("1GiB"), Ok(1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid() {
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn parse_rate_rejects_non_positive() {
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-10").is_err());
+        assert!(parse_rate("inf").is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::{fmt::Write as _, io::{Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use anyhow::{Context, Result};
+
+use crate::{Profile, SampleTree};
+
+// A client that stops reading or writing holds up the others for at most this long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Serves the profile in the Prometheus text format on every request, from its own thread.
+// Requests are answered one at a time, which is plenty for a scraper.
+pub fn spawn(addr: SocketAddr, profile: Arc<Mutex<Profile>>, total_length: u64, min_disk_fraction: Option<f64>) -> Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("binding {}", addr))?;
+    let start = Instant::now();
+    // --load samples don't count towards the rate
+    let start_samples = profile.lock().unwrap().total_samples;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            // a failed scrape must not stop the server
+            let _ = stream.map_err(anyhow::Error::from).and_then(|mut stream| {
+                let body = {
+                    let profile = profile.lock().unwrap();
+                    let rate = (profile.total_samples - start_samples) as f64 / start.elapsed().as_secs_f64();
+                    render(&profile, total_length, rate, min_disk_fraction)
+                };
+                respond(&mut stream, &body)
+            });
+        }
+    });
+    Ok(())
+}
+
+fn respond(stream: &mut TcpStream, body: &str) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    // the request itself doesn't matter, every path gets the metrics
+    let mut request = [0; 4096];
+    let _ = stream.read(&mut request)?;
+    write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n", body.len())?;
+    stream.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+fn render(profile: &Profile, total_length: u64, rate: f64, min_disk_fraction: Option<f64>) -> String {
+    let total_samples = profile.total_samples;
+    let mut res = String::new();
+    res.push_str("# HELP btdu_samples_total Samples taken.\n# TYPE btdu_samples_total counter\n");
+    let _ = writeln!(res, "btdu_samples_total {}", total_samples);
+    res.push_str("# HELP btdu_samples_per_second Samples taken per second since start.\n# TYPE btdu_samples_per_second gauge\n");
+    let _ = writeln!(res, "btdu_samples_per_second {}", rate);
+    res.push_str("# HELP btdu_resolution_bytes Bytes represented by one sample.\n# TYPE btdu_resolution_bytes gauge\n");
+    let _ = writeln!(res, "btdu_resolution_bytes {}", total_length / total_samples.max(1) as u64);
+
+    res.push_str("# HELP btdu_errors_total Samples that could not be resolved, by reason.\n# TYPE btdu_errors_total counter\n");
+    for (k,v) in &profile.errors.kinds {
+        let _ = writeln!(res, "btdu_errors_total{{kind=\"{}\"}} {}", label_value(k), v);
+    }
+
+    res.push_str("# HELP btdu_path_bytes Estimated disk usage below a path, for paths above --min-pct.\n# TYPE btdu_path_bytes gauge\n");
+    if total_samples > 0 {
+        write_paths(&mut res, &profile.tree, "", total_samples, total_length, min_disk_fraction);
+    }
+    res
+}
+
+fn write_paths(res: &mut String, node: &SampleTree, prefix: &str, total_samples: usize, total_length: u64, min_disk_fraction: Option<f64>) {
+    let mut c: Vec<_> = node.children.iter().collect();
+    c.sort_by_key(|&(k, _)| k);
+    for (k,v) in c {
+        let disk_fraction = (v.total as f64) / (total_samples as f64);
+        if min_disk_fraction.is_some_and(|min_disk_fraction| disk_fraction < min_disk_fraction) {
+            continue;
+        }
+        let path = format!("{}/{}", prefix, k);
+        let _ = writeln!(res, "btdu_path_bytes{{path=\"{}\"}} {}", label_value(&path), (total_length as f64 * disk_fraction) as u64);
+        write_paths(res, v, &path, total_samples, total_length, min_disk_fraction);
+    }
+}
+
+fn label_value(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
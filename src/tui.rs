@@ -0,0 +1,298 @@
+use std::{io::{self, Write}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread, time::Duration};
+
+use anyhow::Result;
+use crossterm::{cursor, event::{self, Event, KeyCode, KeyEvent, KeyModifiers}, execute, queue, style::{Attribute, Print, SetAttribute}, terminal::{self, ClearType}};
+
+use crate::{format_count, format_delta, pad_width, take_width, truncate_middle, Profile, SampleTree};
+
+// The screen is redrawn this often while no key is pressed
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+// Runs the browser on its own thread. `stop` is set once it is closed (or fails)
+// so that the sampling loop can end.
+pub fn spawn(profile: Arc<Mutex<Profile>>, total_length: u64, logical: bool, stop: Arc<AtomicBool>) -> thread::JoinHandle<Result<()>> {
+    thread::spawn(move || {
+        let res = run(&profile, total_length, logical);
+        stop.store(true, Ordering::Relaxed);
+        res
+    })
+}
+
+fn run(profile: &Mutex<Profile>, total_length: u64, logical: bool) -> Result<()> {
+    let mut out = io::stdout();
+    terminal::enable_raw_mode()?;
+    let res = execute!(out, terminal::EnterAlternateScreen, cursor::Hide)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| Browser::new(logical).browse(&mut out, profile, total_length));
+    // the terminal is restored even when drawing failed
+    let _ = execute!(out, cursor::Show, terminal::LeaveAlternateScreen);
+    terminal::disable_raw_mode()?;
+    res
+}
+
+fn sorted_children(node: &SampleTree) -> Vec<(&String, &SampleTree)> {
+    let mut c: Vec<_> = node.children.iter().collect();
+    c.sort_by(|(ka,va), (kb,vb)| vb.total.cmp(&va.total).then_with(|| ka.cmp(kb)));
+    c
+}
+
+// What the value column of the list shows, switched with u
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    // estimated size, physical unless --logical
+    Size,
+    Samples,
+}
+
+// Copy of the tree marked with m. Until it is cleared with c, rows show how much they changed since.
+struct Baseline {
+    tree: SampleTree,
+    total_samples: usize,
+    // bytes the samples were spread over, sizes are not comparable once it changes
+    total_length: u64,
+}
+
+impl Baseline {
+    fn new(profile: &Profile) -> Self {
+        Self {
+            tree: profile.tree.clone(),
+            total_samples: profile.total_samples,
+            total_length: profile.total_length,
+        }
+    }
+
+    // Change in estimated bytes of the node at `path`, which now has `total` samples
+    fn delta<'a>(&self, path: impl Iterator<Item=&'a str>, total: usize, bytes_per_sample: f64) -> i64 {
+        let baseline_bytes_per_sample = self.total_length as f64 / self.total_samples.max(1) as f64;
+        (total as f64 * bytes_per_sample - self.tree.total_at(path) as f64 * baseline_bytes_per_sample) as i64
+    }
+}
+
+struct Browser {
+    // names from the root down to the node whose children are listed
+    path: Vec<String>,
+    // child under the cursor, kept by name so it doesn't move while the order changes
+    selected: Option<String>,
+    // first visible row
+    scroll: usize,
+    column: Column,
+    // --logical, only for the key help
+    logical: bool,
+    baseline: Option<Baseline>,
+}
+
+impl Browser {
+    fn new(logical: bool) -> Self {
+        Self {
+            path: Vec::new(),
+            selected: None,
+            scroll: 0,
+            column: Column::Size,
+            logical,
+            baseline: None,
+        }
+    }
+
+    fn browse<W: Write>(&mut self, out: &mut W, profile: &Mutex<Profile>, total_length: u64) -> Result<()> {
+        loop {
+            self.draw(out, &profile.lock().unwrap(), total_length)?;
+            if !event::poll(REFRESH_INTERVAL)? {
+                continue;
+            }
+            // resizes and other events only trigger a redraw
+            if let Event::Key(key) = event::read()? {
+                let (_, rows) = terminal::size()?;
+                if !self.handle_key(key, &profile.lock().unwrap(), list_height(rows)) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Node whose children are listed. Nodes folded away by --max-nodes pop the path
+    // back to the deepest one that still exists.
+    fn node<'a>(&mut self, tree: &'a SampleTree) -> &'a SampleTree {
+        loop {
+            if let Some(node) = tree.get(self.path.iter().map(|s| s.as_str())) {
+                return node;
+            }
+            self.path.pop();
+            self.selected = None;
+        }
+    }
+
+    // A baseline of a different total length (the resolution changed) would show bogus changes
+    fn drop_stale_baseline(&mut self, profile: &Profile) {
+        if self.baseline.as_ref().is_some_and(|baseline| baseline.total_length != profile.total_length) {
+            self.baseline = None;
+        }
+    }
+
+    fn selected_index(&self, children: &[(&String, &SampleTree)]) -> usize {
+        self.selected.as_ref()
+            .and_then(|selected| children.iter().position(|(k, _)| *k == selected))
+            .unwrap_or(0)
+    }
+
+    // Returns false when the browser should be closed
+    fn handle_key(&mut self, key: KeyEvent, profile: &Profile, page: usize) -> bool {
+        let node = self.node(&profile.tree);
+        let children = sorted_children(node);
+        let index = self.selected_index(&children);
+        let last = children.len().saturating_sub(1);
+        let new_index = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Char('k') => index.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => (index + 1).min(last),
+            KeyCode::PageUp => index.saturating_sub(page),
+            KeyCode::PageDown => (index + page).min(last),
+            KeyCode::Char('u') => {
+                self.column = match self.column {
+                    Column::Size => Column::Samples,
+                    Column::Samples => Column::Size,
+                };
+                return true;
+            },
+            KeyCode::Char('m') => {
+                self.baseline = Some(Baseline::new(profile));
+                return true;
+            },
+            KeyCode::Char('c') => {
+                self.baseline = None;
+                return true;
+            },
+            KeyCode::Home => 0,
+            KeyCode::End => last,
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => {
+                if let Some((k, v)) = children.get(index) {
+                    if !v.children.is_empty() {
+                        self.path.push(k.to_string());
+                        self.selected = None;
+                        self.scroll = 0;
+                    }
+                }
+                return true;
+            },
+            KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => {
+                // the cursor goes back to the node that was left
+                if let Some(name) = self.path.pop() {
+                    self.selected = Some(name);
+                }
+                return true;
+            },
+            _ => return true,
+        };
+        self.selected = children.get(new_index).map(|(k, _)| k.to_string());
+        true
+    }
+
+    fn draw<W: Write>(&mut self, out: &mut W, profile: &Profile, total_length: u64) -> Result<()> {
+        let (cols, rows) = terminal::size()?;
+        let cols = cols as usize;
+        let height = list_height(rows);
+        self.drop_stale_baseline(profile);
+        let node = self.node(&profile.tree);
+        let children = sorted_children(node);
+        let index = self.selected_index(&children);
+        if index < self.scroll {
+            self.scroll = index;
+        } else if index >= self.scroll + height {
+            self.scroll = index + 1 - height;
+        }
+
+        let resolution = total_length / profile.total_samples.max(1) as u64;
+        let mut header = format!("/{}  samples={} resolution={}", self.path.join("/"), format_count(profile.total_samples), bytesize::to_string(resolution, true));
+        if self.baseline.is_some() {
+            header.push_str("  changes since mark");
+        }
+        queue!(out, cursor::MoveTo(0, 0), SetAttribute(Attribute::Reverse), Print(fit(&header, cols)), SetAttribute(Attribute::Reset))?;
+
+        for (row, (k, v)) in children.iter().enumerate().skip(self.scroll).take(height) {
+            let disk_fraction = if profile.total_samples > 0 {
+                v.total as f64 / profile.total_samples as f64
+            } else {
+                0.0
+            };
+            let value = match self.column {
+                Column::Size => bytesize::to_string((total_length as f64 * disk_fraction) as u64, true),
+                Column::Samples => format_count(v.total),
+            };
+            let mut columns = format!("{:>16} {:>5.1}%  ", value, disk_fraction * 100.0);
+            if let Some(baseline) = &self.baseline {
+                let path = self.path.iter().map(|s| s.as_str()).chain(std::iter::once(k.as_str()));
+                let delta = baseline.delta(path, v.total, total_length as f64 / profile.total_samples.max(1) as f64);
+                columns.push_str(&format!("{:>12}  ", format_delta(delta)));
+            }
+            // nodes that can be opened end with a slash like directories in ls -F
+            let mut name = k.to_string();
+            if !v.children.is_empty() {
+                name.push('/');
+            }
+            let name = truncate_middle(&name, cols.saturating_sub(columns.len()).max(1));
+            let line = fit(&format!("{}{}", columns, name), cols);
+            let y = (row - self.scroll + 1) as u16;
+            if row == index {
+                queue!(out, cursor::MoveTo(0, y), SetAttribute(Attribute::Reverse), Print(line), SetAttribute(Attribute::Reset))?;
+            } else {
+                queue!(out, cursor::MoveTo(0, y), Print(line))?;
+            }
+        }
+        let drawn = children.len().saturating_sub(self.scroll).min(height);
+        queue!(out, cursor::MoveTo(0, drawn as u16 + 1), terminal::Clear(ClearType::FromCursorDown))?;
+
+        // sizes are physical unless --logical, u can't switch between the two
+        let size = if self.logical { "logical size" } else { "physical size" };
+        let footer = format!("↑/↓ select  Enter/→ open  Backspace/← up  u {}/samples  m mark  c clear mark  q quit", size);
+        queue!(out, cursor::MoveTo(0, rows.saturating_sub(1)), Print(fit(&footer, cols)))?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// Rows left for the list between the header and the footer
+fn list_height(rows: u16) -> usize {
+    (rows as usize).saturating_sub(2).max(1)
+}
+
+// Cuts or pads a line to exactly `width` characters so it overwrites the previous frame
+fn fit(s: &str, width: usize) -> String {
+    pad_width(&take_width(s.chars(), width), width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn mark_and_clear_baseline() {
+        let mut profile = Profile { total_samples: 4, total_length: 4096, ..Profile::default() };
+        profile.tree.add_samples(["DATA", "a"].into_iter(), 4);
+        let mut browser = Browser::new(false);
+        assert!(browser.handle_key(key('m'), &profile, 10));
+
+        // half of the next 4 samples land in a new directory, which takes half of a's space
+        profile.tree.add_samples(["DATA", "a"].into_iter(), 2);
+        profile.tree.add_samples(["DATA", "b"].into_iter(), 2);
+        profile.total_samples = 8;
+        let baseline = browser.baseline.as_ref().unwrap();
+        assert_eq!(baseline.delta(["DATA", "a"].into_iter(), 6, 512.0), -1024);
+        assert_eq!(baseline.delta(["DATA", "b"].into_iter(), 2, 512.0), 1024);
+        assert_eq!(baseline.delta(["DATA"].into_iter(), 8, 512.0), 0);
+
+        assert!(browser.handle_key(key('c'), &profile, 10));
+        assert!(browser.baseline.is_none());
+
+        // marking again replaces the baseline, a new total length drops it
+        browser.handle_key(key('m'), &profile, 10);
+        browser.drop_stale_baseline(&profile);
+        assert_eq!(browser.baseline.as_ref().map(|b| b.total_samples), Some(8));
+        profile.total_length = 8192;
+        browser.drop_stale_baseline(&profile);
+        assert!(browser.baseline.is_none());
+    }
+}
@@ -0,0 +1,89 @@
+// Samples a freshly made filesystem holding files of known sizes.
+// Needs root and mkfs.btrfs: cargo test --features ci-btrfs
+#![cfg(feature = "ci-btrfs")]
+
+use std::{fs, path::{Path, PathBuf}, process::Command};
+
+use serde_json::Value;
+
+const MIB: u64 = 1024 * 1024;
+
+// A sparse image formatted as btrfs and loop-mounted, unmounted and removed on drop
+struct Image {
+    file: PathBuf,
+    mount_point: PathBuf,
+}
+
+impl Image {
+    fn new(name: &str, size: u64) -> Self {
+        let base = std::env::temp_dir().join(format!("btdu-rs-test-{}-{}", name, std::process::id()));
+        let file = base.with_extension("img");
+        let mount_point = base;
+        fs::File::create(&file).unwrap().set_len(size).unwrap();
+        run(Command::new("mkfs.btrfs").arg("-q").arg(&file));
+        fs::create_dir_all(&mount_point).unwrap();
+        run(Command::new("mount").args(["-o", "loop"]).arg(&file).arg(&mount_point));
+        Self { file, mount_point }
+    }
+
+    fn write_file(&self, name: &str, size: u64) {
+        // not zeroes, so that the data would survive compression as is
+        let mut state = 0x2545f4914f6cdd1du64;
+        let data: Vec<u8> = (0..size).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        fs::write(self.mount_point.join(name), data).unwrap();
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(&self.mount_point).status();
+        let _ = fs::remove_dir(&self.mount_point);
+        let _ = fs::remove_file(&self.file);
+    }
+}
+
+fn run(cmd: &mut Command) {
+    let status = cmd.status().unwrap();
+    assert!(status.success(), "{:?} failed: {}", cmd, status);
+}
+
+fn sample_json(path: &Path) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_btdu-rs"))
+        .args(["--no-tui", "--json", "--quiet", "--seed", "1", "--samples", "20000", "--min-pct", "0"])
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn child<'a>(node: &'a Value, name: &str) -> &'a Value {
+    node["children"].as_array().unwrap().iter()
+        .find(|c| c["name"] == name)
+        .unwrap_or_else(|| panic!("no {} below {}", name, node["name"]))
+}
+
+fn assert_close(node: &Value, expected: u64) {
+    let bytes = node["disk_bytes"].as_u64().unwrap();
+    // 20000 samples over a few hundred MiB resolve well below that
+    let tolerance = expected / 10;
+    assert!(bytes.abs_diff(expected) <= tolerance, "{} has {} bytes, expected {}", node["name"], bytes, expected);
+}
+
+#[test]
+fn files_are_attributed_to_their_paths() {
+    let image = Image::new("files", 1024 * MIB);
+    image.write_file("large", 96 * MIB);
+    image.write_file("small", 32 * MIB);
+    run(Command::new("sync").arg("-f").arg(&image.mount_point));
+
+    let tree = sample_json(&image.mount_point);
+    let data = child(&tree, "DATA");
+    assert_close(child(data, "large"), 96 * MIB);
+    assert_close(child(data, "small"), 32 * MIB);
+}